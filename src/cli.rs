@@ -0,0 +1,292 @@
+use std::fmt;
+
+use super::output::Format;
+use super::trace::Category;
+
+pub const USAGE: &str = "usage:
+  r2engine run FILE      evaluate a program and print every variable
+  r2engine check FILE    parse a program and check its units without evaluating it
+  r2engine eval EXPR     evaluate a single expression, e.g. '2[km] / 30[min] as [km/h]'
+  r2engine fmt FILE      rewrite a program in the canonical format, with --check
+                         only report whether it already is
+  r2engine repl          start an interactive session
+
+options:
+  --csv FILE                 read csv{column} from FILE, may be repeated
+  --output text|json|csv     how `run` writes the variables, json and csv
+                             give each one's unit, dimensions and expression
+  --no-expressions           leave the expression and doc comment out of
+                             json and csv
+  --derived                  print values in derived units where one
+                             matches, J rather than kg·m²/s²
+  --trace parse,eval,units   write what the parser, interpreter and unit
+                             derivation do to stderr, one event per line
+
+FILE may be - to read the program from stdin";
+
+/// What the command line asked for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run {
+        file: String,
+    },
+    Check {
+        file: String,
+    },
+    Eval {
+        expression: String,
+    },
+    /// with `check` the file is not rewritten
+    Fmt {
+        file: String,
+        check: bool,
+    },
+    Repl,
+    Help,
+}
+
+/// Exit codes, so scripts can tell a broken program from a broken invocation
+pub mod exit_code {
+    pub const SUCCESS: i32 = 0;
+    /// the program parsed, but evaluating or checking it failed
+    pub const EVAL_ERROR: i32 = 1;
+    /// the program could not be parsed
+    pub const PARSE_ERROR: i32 = 2;
+    /// `fmt --check` found the program is not formatted
+    pub const UNFORMATTED: i32 = 3;
+    /// the command line was not understood
+    pub const USAGE: i32 = 64;
+    /// a csv file could not be parsed
+    pub const BAD_DATA: i32 = 65;
+    /// the input file could not be read
+    pub const NO_INPUT: i32 = 66;
+    /// the formatted program could not be written back
+    pub const CANNOT_WRITE: i32 = 73;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error: {}\n\n{}", self.0, USAGE)
+    }
+}
+
+/// The command for `args`, without the program name
+pub fn parse_args(args: &[String]) -> Result<Command, UsageError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => Err(UsageError("missing command".to_string())),
+        ["help" | "-h" | "--help", ..] => Ok(Command::Help),
+        ["run", file] => Ok(Command::Run {
+            file: file.to_string(),
+        }),
+        ["check", file] => Ok(Command::Check {
+            file: file.to_string(),
+        }),
+        ["eval", expression] => Ok(Command::Eval {
+            expression: expression.to_string(),
+        }),
+        ["fmt", "--check", file] | ["fmt", file, "--check"] => Ok(Command::Fmt {
+            file: file.to_string(),
+            check: true,
+        }),
+        ["fmt", file] if *file != "--check" => Ok(Command::Fmt {
+            file: file.to_string(),
+            check: false,
+        }),
+        ["repl"] => Ok(Command::Repl),
+        ["run" | "check" | "fmt", ..] => Err(UsageError(format!("`{}` takes one FILE", args[0]))),
+        ["repl", ..] => Err(UsageError("`repl` takes no arguments".to_string())),
+        ["eval", ..] => Err(UsageError(
+            "`eval` takes one expression, quote it to keep it together".to_string(),
+        )),
+        [command, ..] => Err(UsageError(format!("unknown command `{}`", command))),
+    }
+}
+
+/// The options that apply to every command
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Options {
+    pub trace: Vec<Category>,
+    /// the csv files `csv{column}` reads from
+    pub csv_files: Vec<String>,
+    pub output: Format,
+    /// leave each variable's expression out of json and csv
+    pub no_expressions: bool,
+    /// print values in a matching derived unit, J rather than kg·m²/s²
+    pub derived_units: bool,
+}
+
+/// The value after the option at `index`, removing both from `args`
+fn take_value(args: &mut Vec<String>, index: usize, missing: &str) -> Result<String, UsageError> {
+    if index + 1 == args.len() {
+        return Err(UsageError(missing.to_string()));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(value)
+}
+
+/// Remove every `--trace CATEGORIES`, `--csv FILE`, `--output FORMAT`,
+/// `--no-expressions` and `--derived` from `args`, wherever they are. Trace categories are
+/// comma separated and the first two may be repeated
+pub fn take_options(args: &mut Vec<String>) -> Result<Options, UsageError> {
+    let mut options = Options::default();
+    while let Some(index) = args.iter().position(|arg| arg == "--no-expressions") {
+        args.remove(index);
+        options.no_expressions = true;
+    }
+    while let Some(index) = args.iter().position(|arg| arg == "--derived") {
+        args.remove(index);
+        options.derived_units = true;
+    }
+    while let Some(index) = args.iter().position(|arg| arg == "--csv") {
+        let file = take_value(args, index, "`--csv` takes a FILE")?;
+        options.csv_files.push(file);
+    }
+    let mut output = None;
+    while let Some(index) = args.iter().position(|arg| arg == "--output") {
+        if output.is_some() {
+            return Err(UsageError("`--output` given twice".to_string()));
+        }
+        let name = take_value(args, index, "`--output` takes text, json or csv")?;
+        output = Some(Format::from_name(&name).ok_or_else(|| {
+            UsageError(format!(
+                "unknown output format `{}`, expected text, json or csv",
+                name
+            ))
+        })?);
+    }
+    options.output = output.unwrap_or_default();
+    while let Some(index) = args.iter().position(|arg| arg == "--trace") {
+        let names = take_value(
+            args,
+            index,
+            "`--trace` takes a list of parse, eval or units",
+        )?;
+        for name in names.split(',') {
+            match Category::from_name(name) {
+                Some(category) => options.trace.push(category),
+                None => {
+                    return Err(UsageError(format!(
+                        "unknown trace category `{}`, expected parse, eval or units",
+                        name
+                    )))
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// How a file is named in diagnostics, stdin has no name of its own
+pub fn display_name(file: &str) -> &str {
+    match file {
+        "-" => "<stdin>",
+        file => file,
+    }
+}
+
+#[test]
+fn test_parse_args() {
+    let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+
+    assert_eq!(
+        parse_args(&args(&["run", "test.r2"])),
+        Ok(Command::Run {
+            file: "test.r2".to_string()
+        })
+    );
+    assert_eq!(
+        parse_args(&args(&["check", "-"])),
+        Ok(Command::Check {
+            file: "-".to_string()
+        })
+    );
+    assert_eq!(
+        parse_args(&args(&["eval", "1[km] as [m]"])),
+        Ok(Command::Eval {
+            expression: "1[km] as [m]".to_string()
+        })
+    );
+    assert_eq!(
+        parse_args(&args(&["fmt", "test.r2"])),
+        Ok(Command::Fmt {
+            file: "test.r2".to_string(),
+            check: false
+        })
+    );
+    assert_eq!(
+        parse_args(&args(&["fmt", "test.r2", "--check"])),
+        Ok(Command::Fmt {
+            file: "test.r2".to_string(),
+            check: true
+        })
+    );
+    assert_eq!(parse_args(&args(&["repl"])), Ok(Command::Repl));
+    assert_eq!(parse_args(&args(&["--help"])), Ok(Command::Help));
+
+    assert!(parse_args(&args(&[])).is_err());
+    assert!(parse_args(&args(&["run"])).is_err());
+    assert!(parse_args(&args(&["fmt", "--check"])).is_err());
+    assert!(parse_args(&args(&["eval", "1[km]", "as", "[m]"])).is_err());
+    assert_eq!(
+        parse_args(&args(&["frobnicate", "test.r2"])),
+        Err(UsageError("unknown command `frobnicate`".to_string()))
+    );
+}
+
+#[test]
+fn test_take_options() {
+    let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+
+    let mut run = args(&[
+        "--trace",
+        "parse,units",
+        "run",
+        "--csv",
+        "sides.csv",
+        "test.r2",
+        "--trace",
+        "eval",
+        "--csv",
+        "costs.csv",
+        "--output",
+        "json",
+        "--no-expressions",
+        "--derived",
+    ]);
+    assert_eq!(
+        take_options(&mut run),
+        Ok(Options {
+            trace: vec![Category::Parse, Category::Units, Category::Eval],
+            csv_files: vec!["sides.csv".to_string(), "costs.csv".to_string()],
+            output: Format::Json,
+            no_expressions: true,
+            derived_units: true,
+        })
+    );
+    assert_eq!(run, args(&["run", "test.r2"]));
+
+    let mut eval = args(&["eval", "1[m]"]);
+    assert_eq!(take_options(&mut eval), Ok(Options::default()));
+    assert_eq!(eval, args(&["eval", "1[m]"]));
+
+    assert!(take_options(&mut args(&["run", "test.r2", "--trace"])).is_err());
+    assert!(take_options(&mut args(&["run", "test.r2", "--csv"])).is_err());
+    assert!(take_options(&mut args(&["run", "test.r2", "--output", "xml"])).is_err());
+    assert_eq!(
+        take_options(&mut args(&["--trace", "lexer", "run", "test.r2"])),
+        Err(UsageError(
+            "unknown trace category `lexer`, expected parse, eval or units".to_string()
+        ))
+    );
+    assert_eq!(
+        take_options(&mut args(&[
+            "run", "--output", "json", "test.r2", "--output", "csv"
+        ])),
+        Err(UsageError("`--output` given twice".to_string()))
+    );
+}
@@ -0,0 +1,293 @@
+use super::error::{CsvError, EvalError};
+use super::types::Span;
+
+/// A csv file whose first record names its columns. Cells are kept as
+/// text and only read as numbers when their column is used, so a table may
+/// have columns of labels that no program refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub file_name: String,
+    pub headers: Vec<String>,
+    /// each record after the header, with the line it starts on
+    rows: Vec<(usize, Vec<String>)>,
+}
+
+/// The records of `source` and the lines they start on. Fields may be
+/// quoted, "like this", to hold commas, newlines or "" for a quote
+fn records(file_name: &str, source: &str) -> Result<Vec<(usize, Vec<String>)>, CsvError> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push((record_line, std::mem::take(&mut record)));
+                line += 1;
+                record_line = line;
+            }
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (_, c) => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err(CsvError {
+            file_name: file_name.to_string(),
+            line: record_line,
+            message: "unterminated quoted field".to_string(),
+        });
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
+    }
+
+    // blank lines separate nothing, a trailing one is usual
+    records.retain(|(_, record)| !(record.len() == 1 && record[0].trim().is_empty()));
+    Ok(records)
+}
+
+impl Table {
+    /// Parse `source`, checking the header names every column once and
+    /// every row has a cell for each column
+    pub fn parse(file_name: &str, source: &str) -> Result<Table, CsvError> {
+        let error = |line: usize, message: String| CsvError {
+            file_name: file_name.to_string(),
+            line,
+            message,
+        };
+
+        let mut records = records(file_name, source)?.into_iter();
+        let (header_line, headers) = records
+            .next()
+            .ok_or_else(|| error(1, "the file is empty, expected a header".to_string()))?;
+        let headers: Vec<String> = headers
+            .iter()
+            .map(|header| header.trim().to_string())
+            .collect();
+
+        for (index, header) in headers.iter().enumerate() {
+            if header.is_empty() {
+                return Err(error(
+                    header_line,
+                    format!("column {} of the header has no name", index + 1),
+                ));
+            }
+            if headers[..index].contains(header) {
+                return Err(error(
+                    header_line,
+                    format!("the header names column `{}` more than once", header),
+                ));
+            }
+        }
+
+        let rows: Vec<(usize, Vec<String>)> = records.collect();
+        for (line, row) in &rows {
+            if row.len() != headers.len() {
+                return Err(error(
+                    *line,
+                    format!(
+                        "the row has {} cells but the header names {} columns",
+                        row.len(),
+                        headers.len()
+                    ),
+                ));
+            }
+        }
+
+        Ok(Table {
+            file_name: file_name.to_string(),
+            headers,
+            rows,
+        })
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// The numbers in column `name`, which the table must have
+    fn column(&self, name: &str, span: Span) -> Result<Vec<f64>, EvalError> {
+        let index = self
+            .headers
+            .iter()
+            .position(|header| header == name)
+            .expect("column should be in the table");
+
+        self.rows
+            .iter()
+            .map(|(line, row)| {
+                row[index]
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| EvalError::InvalidCell {
+                        column: name.to_string(),
+                        file_name: self.file_name.clone(),
+                        line: *line,
+                        cell: row[index].clone(),
+                        span,
+                    })
+            })
+            .collect()
+    }
+}
+
+/// The one table among `tables` with a column called `name`
+pub fn find_table<'a>(tables: &'a [Table], name: &str, span: Span) -> Result<&'a Table, EvalError> {
+    let found: Vec<&Table> = tables
+        .iter()
+        .filter(|table| table.headers.iter().any(|header| header == name))
+        .collect();
+
+    match found.as_slice() {
+        [table] => Ok(table),
+        [] => {
+            let mut available: Vec<String> = vec![];
+            for header in tables.iter().flat_map(|table| &table.headers) {
+                if !available.contains(header) {
+                    available.push(header.clone());
+                }
+            }
+            Err(EvalError::UnknownColumn {
+                column: name.to_string(),
+                available,
+                span,
+            })
+        }
+        found => Err(EvalError::AmbiguousColumn {
+            column: name.to_string(),
+            file_names: found.iter().map(|table| table.file_name.clone()).collect(),
+            span,
+        }),
+    }
+}
+
+/// The numbers in the column called `name`, from whichever table has it
+pub fn column(tables: &[Table], name: &str, span: Span) -> Result<Vec<f64>, EvalError> {
+    find_table(tables, name, span)?.column(name, span)
+}
+
+#[test]
+fn test_parse_table() {
+    let table = Table::parse(
+        "sides.csv",
+        "name, height, width\r\nfirst,1,2.5\n\"second, and last\",3e3,\"4\"\n\n",
+    )
+    .unwrap();
+    assert_eq!(table.headers, vec!["name", "height", "width"]);
+    assert_eq!(table.row_count(), 2);
+    assert_eq!(
+        table.rows[1],
+        (
+            3,
+            vec![
+                "second, and last".to_string(),
+                "3e3".to_string(),
+                "4".to_string()
+            ]
+        )
+    );
+
+    let tables = [table];
+    assert_eq!(
+        column(&tables, "height", Span::default()),
+        Ok(vec![1.0, 3000.0])
+    );
+    assert_eq!(
+        column(&tables, "width", Span::default()),
+        Ok(vec![2.5, 4.0])
+    );
+}
+
+#[test]
+fn test_parse_table_errors() {
+    let message =
+        |source: &str| Table::parse("t.csv", source).map_err(|error| (error.line, error.message));
+
+    assert_eq!(
+        message(""),
+        Err((1, "the file is empty, expected a header".to_string()))
+    );
+    assert_eq!(
+        message("a,,c\n1,2,3\n"),
+        Err((1, "column 2 of the header has no name".to_string()))
+    );
+    assert_eq!(
+        message("a,b,a\n1,2,3\n"),
+        Err((1, "the header names column `a` more than once".to_string()))
+    );
+    assert_eq!(
+        message("a,b\n1,2\n3\n"),
+        Err((
+            3,
+            "the row has 1 cells but the header names 2 columns".to_string()
+        ))
+    );
+    assert_eq!(
+        message("a,b\n\"1,2\n"),
+        Err((2, "unterminated quoted field".to_string()))
+    );
+}
+
+#[test]
+fn test_column_errors() {
+    let tables = [
+        Table::parse("a.csv", "x,y\n1,\n2,two\n").unwrap(),
+        Table::parse("b.csv", "x,z\n1,2\n").unwrap(),
+    ];
+
+    assert_eq!(
+        column(&tables, "y", Span::default())
+            .unwrap_err()
+            .to_string(),
+        "column `y` of a.csv is empty on line 2"
+    );
+    assert!(matches!(
+        column(&tables, "z", Span::default()),
+        Ok(values) if values == vec![2.0]
+    ));
+    assert_eq!(
+        column(&tables, "x", Span::default())
+            .unwrap_err()
+            .to_string(),
+        "csv column `x` is in more than one file: a.csv, b.csv"
+    );
+    assert_eq!(
+        column(&tables, "w", Span::default())
+            .unwrap_err()
+            .to_string(),
+        "no csv column `w`, the columns are `x`, `y`, `z`"
+    );
+    assert_eq!(
+        column(&[], "w", Span::default()).unwrap_err().to_string(),
+        "no csv column `w`, no csv files were given, use --csv FILE"
+    );
+
+    let tables = [Table::parse("a.csv", "y\n1\ntwo\n").unwrap()];
+    assert_eq!(
+        column(&tables, "y", Span::default())
+            .unwrap_err()
+            .to_string(),
+        "`two` in column `y` of a.csv, line 3, is not a number"
+    );
+}
@@ -0,0 +1,486 @@
+use std::fmt;
+
+use super::types::{BinaryOperation, Quantity, Span, Value};
+use super::units;
+
+/// A position in a source file, lines and columns count from 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file_name: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file_name, self.line, self.column)
+    }
+}
+
+/// The source line at a location, with a caret under the column:
+///
+/// ```text
+///   |
+/// 3 | a = 1[furlongs];
+///   |       ^
+/// ```
+pub fn render_snippet(source_line: &str, line: usize, column: usize) -> String {
+    let gutter = " ".repeat(line.to_string().len());
+    format!(
+        "{gutter} |\n{line} | {source_line}\n{gutter} | {caret:>column$}",
+        caret = "^",
+    )
+}
+
+/// Why a program could not be parsed, and where
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub location: Location,
+    /// the tokens that would have let parsing continue, e.g. `=` or a number
+    pub expected: Vec<String>,
+    /// set when the input was recognised but is invalid, e.g. an unknown unit
+    pub message: Option<String>,
+    pub snippet: String,
+}
+
+impl ParseError {
+    pub fn new(
+        file_name: &str,
+        source_line: &str,
+        line: usize,
+        column: usize,
+        expected: Vec<String>,
+        message: Option<String>,
+    ) -> ParseError {
+        ParseError {
+            location: Location {
+                file_name: file_name.to_string(),
+                line,
+                column,
+            },
+            expected,
+            message,
+            snippet: render_snippet(source_line, line, column),
+        }
+    }
+
+    fn summary(&self) -> String {
+        if let Some(message) = &self.message {
+            return message.clone();
+        }
+        match self.expected.as_slice() {
+            [] => "unexpected input".to_string(),
+            [expected] => format!("expected {}", expected),
+            [expected @ .., last] => format!("expected {} or {}", expected.join(", "), last),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error: {}\n --> {}\n{}",
+            self.summary(),
+            self.location,
+            self.snippet
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Why a csv file could not be loaded, and the line it went wrong on
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvError {
+    pub file_name: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error: {}\n --> {}:{}",
+            self.message, self.file_name, self.line
+        )
+    }
+}
+
+impl std::error::Error for CsvError {}
+
+/// The line and column of a span, and the line it is on
+pub fn position(span: Span, source: &str) -> (usize, usize, &str) {
+    let offset = source.len().saturating_sub(span.from_end);
+    let line_start = source[..offset]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |newline| offset + newline);
+    let line = source[..offset].matches('\n').count() + 1;
+    let column = source[line_start..offset].chars().count() + 1;
+
+    (line, column, &source[line_start..line_end])
+}
+
+/// Why a program could not be evaluated. The span points at the expression
+/// that failed, the arithmetic traits leave it to the interpreter to fill in
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    UndefinedVariable {
+        name: String,
+        span: Span,
+    },
+    DimensionMismatch {
+        lhs: Quantity,
+        rhs: Quantity,
+        op: BinaryOperation,
+        span: Span,
+    },
+    VectorLengthMismatch {
+        lhs: usize,
+        rhs: usize,
+        span: Span,
+    },
+    DivisionByZero {
+        span: Span,
+    },
+    /// an exponent of a unit does not fit in an i32, m^2000000000 * m^2000000000
+    ExponentOverflow {
+        span: Span,
+    },
+    DimensionedExponent {
+        exponent: Quantity,
+        span: Span,
+    },
+    InvalidPower {
+        base: Quantity,
+        power: Value,
+        span: Span,
+    },
+    ConversionMismatch {
+        from: Quantity,
+        to: Quantity,
+        span: Span,
+    },
+    AnnotationMismatch {
+        name: String,
+        declared: Quantity,
+        found: Quantity,
+        span: Span,
+    },
+    UnknownColumn {
+        column: String,
+        /// the columns of every csv file given
+        available: Vec<String>,
+        span: Span,
+    },
+    AmbiguousColumn {
+        column: String,
+        file_names: Vec<String>,
+        span: Span,
+    },
+    InvalidCell {
+        column: String,
+        file_name: String,
+        line: usize,
+        cell: String,
+        span: Span,
+    },
+    /// csv columns used in the same expression with different numbers of rows
+    RowCountMismatch {
+        lhs: (String, usize),
+        rhs: (String, usize),
+        span: Span,
+    },
+}
+
+impl EvalError {
+    pub fn span(&self) -> Span {
+        match self {
+            EvalError::UndefinedVariable { span, .. } => *span,
+            EvalError::DimensionMismatch { span, .. } => *span,
+            EvalError::VectorLengthMismatch { span, .. } => *span,
+            EvalError::DivisionByZero { span } => *span,
+            EvalError::ExponentOverflow { span } => *span,
+            EvalError::DimensionedExponent { span, .. } => *span,
+            EvalError::InvalidPower { span, .. } => *span,
+            EvalError::ConversionMismatch { span, .. } => *span,
+            EvalError::AnnotationMismatch { span, .. } => *span,
+            EvalError::UnknownColumn { span, .. } => *span,
+            EvalError::AmbiguousColumn { span, .. } => *span,
+            EvalError::InvalidCell { span, .. } => *span,
+            EvalError::RowCountMismatch { span, .. } => *span,
+        }
+    }
+
+    /// The same error, pointing at `location`
+    pub fn at(self, location: Span) -> EvalError {
+        match self {
+            EvalError::UndefinedVariable { name, .. } => EvalError::UndefinedVariable {
+                name,
+                span: location,
+            },
+            EvalError::DimensionMismatch { lhs, rhs, op, .. } => EvalError::DimensionMismatch {
+                lhs,
+                rhs,
+                op,
+                span: location,
+            },
+            EvalError::VectorLengthMismatch { lhs, rhs, .. } => EvalError::VectorLengthMismatch {
+                lhs,
+                rhs,
+                span: location,
+            },
+            EvalError::DivisionByZero { .. } => EvalError::DivisionByZero { span: location },
+            EvalError::ExponentOverflow { .. } => EvalError::ExponentOverflow { span: location },
+            EvalError::DimensionedExponent { exponent, .. } => EvalError::DimensionedExponent {
+                exponent,
+                span: location,
+            },
+            EvalError::InvalidPower { base, power, .. } => EvalError::InvalidPower {
+                base,
+                power,
+                span: location,
+            },
+            EvalError::ConversionMismatch { from, to, .. } => EvalError::ConversionMismatch {
+                from,
+                to,
+                span: location,
+            },
+            EvalError::AnnotationMismatch {
+                name,
+                declared,
+                found,
+                ..
+            } => EvalError::AnnotationMismatch {
+                name,
+                declared,
+                found,
+                span: location,
+            },
+            EvalError::UnknownColumn {
+                column, available, ..
+            } => EvalError::UnknownColumn {
+                column,
+                available,
+                span: location,
+            },
+            EvalError::AmbiguousColumn {
+                column, file_names, ..
+            } => EvalError::AmbiguousColumn {
+                column,
+                file_names,
+                span: location,
+            },
+            EvalError::InvalidCell {
+                column,
+                file_name,
+                line,
+                cell,
+                ..
+            } => EvalError::InvalidCell {
+                column,
+                file_name,
+                line,
+                cell,
+                span: location,
+            },
+            EvalError::RowCountMismatch { lhs, rhs, .. } => EvalError::RowCountMismatch {
+                lhs,
+                rhs,
+                span: location,
+            },
+        }
+    }
+
+    /// The error with the location and source line it came from, like a `ParseError`
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let (line, column, source_line) = position(self.span(), source);
+        let location = Location {
+            file_name: file_name.to_string(),
+            line,
+            column,
+        };
+        format!(
+            "error: {}\n --> {}\n{}",
+            self,
+            location,
+            render_snippet(source_line, line, column)
+        )
+    }
+}
+
+fn describe(quantity: &Quantity) -> String {
+    if quantity.is_dimensionless() {
+        "a dimensionless value".to_string()
+    } else {
+        format!("`{}`", units::symbol_for(quantity))
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::UndefinedVariable { name, .. } => {
+                write!(f, "undefined variable `{}`", name)
+            }
+            EvalError::DimensionMismatch { lhs, rhs, op, .. } => {
+                let verb = match op {
+                    BinaryOperation::Add => "add",
+                    BinaryOperation::Subtract => "subtract",
+                    BinaryOperation::Multiply => "multiply",
+                    BinaryOperation::Divide => "divide",
+                    BinaryOperation::Power => "raise",
+                };
+                write!(f, "cannot {} {} and {}", verb, describe(lhs), describe(rhs))
+            }
+            EvalError::VectorLengthMismatch { lhs, rhs, .. } => write!(
+                f,
+                "cannot combine vectors of length {} and {} element by element",
+                lhs, rhs
+            ),
+            EvalError::DivisionByZero { .. } => write!(f, "division by zero"),
+            EvalError::ExponentOverflow { .. } => {
+                write!(f, "the exponents of the unit are too large")
+            }
+            EvalError::DimensionedExponent { exponent, .. } => write!(
+                f,
+                "exponents must be dimensionless, found {}",
+                describe(exponent)
+            ),
+            EvalError::InvalidPower { base, power, .. } => match power {
+                // a whole power always gives whole exponents, they just may not fit
+                Value::Float(power) if power.fract() == 0.0 => write!(
+                    f,
+                    "cannot raise {} to the power {}, the exponents of the unit are too large",
+                    describe(base),
+                    power
+                ),
+                Value::Float(power) => write!(
+                    f,
+                    "cannot raise {} to the power {}, the result has a fractional unit",
+                    describe(base),
+                    power
+                ),
+                Value::Vec(powers) => write!(
+                    f,
+                    "cannot raise {} to the powers {:?}, each element would have a different unit",
+                    describe(base),
+                    powers
+                ),
+            },
+            EvalError::ConversionMismatch { from, to, .. } => {
+                write!(f, "cannot convert {} to {}", describe(from), describe(to))
+            }
+            EvalError::AnnotationMismatch {
+                name,
+                declared,
+                found,
+                ..
+            } => write!(
+                f,
+                "`{}` is declared as {} but evaluates to {}",
+                name,
+                describe(declared),
+                describe(found)
+            ),
+            EvalError::UnknownColumn {
+                column, available, ..
+            } => match available.as_slice() {
+                [] => write!(
+                    f,
+                    "no csv column `{}`, no csv files were given, use --csv FILE",
+                    column
+                ),
+                available => write!(
+                    f,
+                    "no csv column `{}`, the columns are `{}`",
+                    column,
+                    available.join("`, `")
+                ),
+            },
+            EvalError::AmbiguousColumn {
+                column, file_names, ..
+            } => write!(
+                f,
+                "csv column `{}` is in more than one file: {}",
+                column,
+                file_names.join(", ")
+            ),
+            EvalError::InvalidCell {
+                column,
+                file_name,
+                line,
+                cell,
+                ..
+            } => match cell.trim() {
+                "" => write!(
+                    f,
+                    "column `{}` of {} is empty on line {}",
+                    column, file_name, line
+                ),
+                cell => write!(
+                    f,
+                    "`{}` in column `{}` of {}, line {}, is not a number",
+                    cell, column, file_name, line
+                ),
+            },
+            EvalError::RowCountMismatch { lhs, rhs, .. } => write!(
+                f,
+                "csv columns `{}` and `{}` are used together but have {} and {} rows",
+                lhs.0, rhs.0, lhs.1, rhs.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+#[test]
+fn test_parse_error_display() {
+    let error = ParseError::new(
+        "test.r2",
+        "a = 1[m^1]",
+        3,
+        11,
+        vec!["`;`".to_string()],
+        None,
+    );
+    assert_eq!(
+        error.to_string(),
+        "error: expected `;`\n --> test.r2:3:11\n  |\n3 | a = 1[m^1]\n  |           ^"
+    );
+
+    let error = ParseError::new(
+        "test.r2",
+        "a 1[m^1];",
+        1,
+        3,
+        vec!["`=`".to_string(), "`(`".to_string(), "a number".to_string()],
+        None,
+    );
+    assert!(error
+        .to_string()
+        .starts_with("error: expected `=`, `(` or a number\n"));
+}
+
+#[test]
+fn test_eval_error_render() {
+    let source = "a = 1[m];\nb = (a + 1[m^2]);";
+    let error = EvalError::DimensionMismatch {
+        lhs: Quantity::base(super::types::BaseDimension::Length, 1),
+        rhs: Quantity::base(super::types::BaseDimension::Length, 2),
+        op: BinaryOperation::Add,
+        span: Span::default(),
+    }
+    .at(Span {
+        from_end: "(a + 1[m^2]);".len(),
+    });
+    assert_eq!(
+        error.render("test.r2", source),
+        "error: cannot add `m` and `m²`\n --> test.r2:2:5\n  |\n2 | b = (a + 1[m^2]);\n  |     ^"
+    );
+}
@@ -0,0 +1,287 @@
+use super::error::ParseError;
+use super::parser::parse_program;
+use super::types::AstNode;
+
+/// A `#` or `/* */` comment and the byte offset it starts at
+struct Comment<'a> {
+    offset: usize,
+    text: &'a str,
+}
+
+impl Comment<'_> {
+    fn end(&self) -> usize {
+        self.offset + self.text.len()
+    }
+}
+
+/// The source after one statement's `;`, up to and including the next
+#[derive(Default)]
+struct Region<'a> {
+    comments: Vec<Comment<'a>>,
+    /// where the first token of the statement starts, after any comments
+    first_token: Option<usize>,
+    /// where the `;` that ends the statement is, the last region has none
+    end: Option<usize>,
+}
+
+/// `source` split at each `;`, with the comments in between. `#` and `/*`
+/// always start a comment and `;` always ends a statement, so this needs no
+/// more of the grammar than that
+fn regions(source: &str) -> Vec<Region<'_>> {
+    let mut regions = vec![Region::default()];
+    let mut offset = 0;
+    while let Some(c) = source[offset..].chars().next() {
+        let rest = &source[offset..];
+        let region = regions.last_mut().expect("there is always a region");
+        let comment_length = if c == '#' {
+            Some(rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") {
+            Some(rest.find("*/").map_or(rest.len(), |end| end + 2))
+        } else {
+            None
+        };
+
+        match comment_length {
+            Some(length) => {
+                region.comments.push(Comment {
+                    offset,
+                    text: rest[..length].trim_end(),
+                });
+                offset += length;
+                continue;
+            }
+            None if c == ';' => {
+                region.end = Some(offset);
+                regions.push(Region::default());
+            }
+            None if !c.is_whitespace() && region.first_token.is_none() => {
+                region.first_token = Some(offset);
+            }
+            None => {}
+        }
+        offset += c.len_utf8();
+    }
+    regions
+}
+
+/// The formatted lines, and where the last item written ends in the source
+struct Output<'a> {
+    source: &'a str,
+    lines: Vec<String>,
+    last_end: usize,
+}
+
+impl Output<'_> {
+    /// Write the item at `start..end` of the source on a line of its own,
+    /// after an empty line when there was one before it
+    fn push(&mut self, start: usize, end: usize, text: String) {
+        if !self.lines.is_empty() && start >= self.last_end {
+            let lines: Vec<&str> = self.source[self.last_end..start].split('\n').collect();
+            if lines.len() > 2
+                && lines[1..lines.len() - 1]
+                    .iter()
+                    .any(|line| line.trim().is_empty())
+            {
+                self.lines.push(String::new());
+            }
+        }
+        self.lines.push(text);
+        self.last_end = self.last_end.max(end);
+    }
+
+    /// Write `comment` at the end of the last line
+    fn append(&mut self, comment: &Comment) {
+        let line = self.lines.last_mut().expect("the statement was written");
+        line.push(' ');
+        line.push_str(comment.text);
+        self.last_end = comment.end();
+    }
+}
+
+/// `source` formatted the one way it is written: a statement per line with
+/// single spaces around operators, units by their symbol, vectors as
+/// `[1 2 3][m]` and only the parentheses precedence needs. Comments are kept.
+/// One that ends a statement's line stays there, unless it is a `##` doc
+/// comment of the next statement, others go on their own line before the
+/// statement they were in or before. Runs of blank lines become one
+pub fn format_source(file_name: &str, source: &str) -> Result<String, ParseError> {
+    let statements: Vec<AstNode> = parse_program(file_name, source)?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let mut output = Output {
+        source,
+        lines: vec![],
+        last_end: 0,
+    };
+    let regions = regions(source);
+    for (index, region) in regions.iter().enumerate() {
+        let statement = statements.get(index);
+        let previous_end = index
+            .checked_sub(1)
+            .and_then(|previous| regions[previous].end);
+        let mut doc_start = None;
+
+        for comment in &region.comments {
+            let on_statement_line =
+                previous_end.is_some_and(|end| !source[end..comment.offset].contains('\n'));
+            // the parser already gave the statement its doc comments
+            let is_doc = statement.is_some()
+                && comment.text.starts_with("##")
+                && region
+                    .first_token
+                    .is_some_and(|start| comment.offset < start);
+
+            // a `##` after a `;` documents the next statement, so it is
+            // written with it rather than at the end of the line
+            if is_doc {
+                doc_start = doc_start.or(Some(comment.offset));
+            } else if on_statement_line {
+                output.append(comment);
+            } else {
+                output.push(comment.offset, comment.end(), comment.text.to_string());
+            }
+        }
+
+        if let (Some(statement), Some(first_token), Some(end)) =
+            (statement, region.first_token, region.end)
+        {
+            let start = doc_start.unwrap_or(first_token);
+            output.push(start, end + 1, statement.to_string());
+        }
+    }
+
+    Ok(match output.lines.is_empty() {
+        true => String::new(),
+        false => output.lines.join("\n") + "\n",
+    })
+}
+
+/// The first line that formatting `source` changes, or None when it is
+/// already formatted
+pub fn first_unformatted_line(source: &str, formatted: &str) -> Option<usize> {
+    if source == formatted {
+        return None;
+    }
+    if let Some(line) = source
+        .lines()
+        .zip(formatted.lines())
+        .position(|(line, formatted_line)| line != formatted_line)
+    {
+        return Some(line + 1);
+    }
+    // one is the other with lines added, or only the final newline differs
+    let (lines, formatted_lines) = (source.lines().count(), formatted.lines().count());
+    Some(match lines == formatted_lines {
+        true => lines,
+        false => lines.min(formatted_lines) + 1,
+    })
+}
+
+#[test]
+fn test_format_source() {
+    let source = "# inputs\n\
+        wage  [currency/time]=12[USD]/4[month] ;  # before tax\n\
+        ## days off\n\
+        ## each year\n\
+        absence = [ 1  2.5 ][ days ];\n\
+        \n\
+        \n\
+        /* the result */\n\
+        benefit=(wage*(absence+0.5[days]))+-(a)*15% as [ USD ]; b = 1 [ kilometers ^ 2 ] ;\n\
+        c = csv{ height [ km ] } / ((a ^ b) ^ c) # inside\n\
+          + -a ^ 2;\n\
+        # the end\n";
+    let formatted = format_source("test.r2", source).unwrap();
+    assert_eq!(
+        formatted,
+        "# inputs\n\
+        wage[currency/time] = 12[USD] / 4[month]; # before tax\n\
+        ## days off\n\
+        ## each year\n\
+        absence = [1 2.5][day];\n\
+        \n\
+        /* the result */\n\
+        benefit = wage * (absence + 0.5[day]) + -a * 0.15 as [USD];\n\
+        b = 1[km^2];\n\
+        # inside\n\
+        c = csv{height[km]} / (a^b)^c + -a^2;\n\
+        # the end\n"
+    );
+
+    // formatting keeps the meaning, and formatting again changes nothing
+    let statements = |source: &str| -> Vec<AstNode> {
+        parse_program("test.r2", source)
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .map(AstNode::without_spans)
+            .collect()
+    };
+    assert_eq!(statements(&formatted), statements(source));
+    assert_eq!(format_source("test.r2", &formatted).unwrap(), formatted);
+
+    assert_eq!(format_source("test.r2", "").unwrap(), "");
+    assert_eq!(format_source("test.r2", "\n# only\n").unwrap(), "# only\n");
+    assert!(format_source("test.r2", "a = ;").is_err());
+}
+
+#[cfg(test)]
+fn values(source: &str) -> Vec<String> {
+    use super::interpreter::{Interpreter, Memory};
+
+    let mut memory: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    memory.run().unwrap();
+    memory
+        .variables()
+        .into_iter()
+        .map(|(name, value)| format!("{} = {:#}", name, value))
+        .collect()
+}
+
+#[test]
+fn test_format_keeps_values() {
+    let source = "a = 1[km/h/s];\n\
+        b = 2[kg/m/s^2] * 3[m*s^2];\n\
+        c[J/kg/K] = 4[J/kg/K];\n\
+        i = 1[s^-1] * 2[h];\n\
+        e = 2 as [m^-2 * m^2];\n\
+        f = 3[km/m] as [none];\n\
+        j = 5[(km/h)^2/s] as [m^2/s^3];\n\
+        k = (-2)^2 + (-3[m])^2 / 1[m^2] - -2^2;\n";
+    let formatted = format_source("test.r2", source).unwrap();
+    assert_eq!(values(&formatted), values(source));
+    assert_eq!(
+        formatted,
+        "a = 1[km/h/s];\n\
+        b = 2[kg/m/s^2] * 3[m*s^2];\n\
+        c[J/kg/K] = 4[J/kg/K];\n\
+        i = 1[s^-1] * 2[h];\n\
+        e = 2 as [m/m];\n\
+        f = 3[km/m] as [none];\n\
+        j = 5[km^2/h^2/s] as [m^2/s^3];\n\
+        k = (-2)^2 + (-3[m])^2 / 1[m^2] - -2^2;\n"
+    );
+}
+
+#[test]
+fn test_format_doc_comment_after_statement() {
+    // the `##` is the doc comment of `b`, so it is written once, before it
+    let source = "a = 1; ## doc\nb = 2;\nc = 3; ## trailing\n";
+    let formatted = format_source("test.r2", source).unwrap();
+    assert_eq!(formatted, "a = 1;\n## doc\nb = 2;\nc = 3; ## trailing\n");
+    assert_eq!(format_source("test.r2", &formatted).unwrap(), formatted);
+}
+
+#[test]
+fn test_first_unformatted_line() {
+    assert_eq!(first_unformatted_line("a = 1;\n", "a = 1;\n"), None);
+    assert_eq!(
+        first_unformatted_line("a = 1;\nb=2;\n", "a = 1;\nb = 2;\n"),
+        Some(2)
+    );
+    assert_eq!(first_unformatted_line("a = 1;", "a = 1;\n"), Some(1));
+    assert_eq!(first_unformatted_line("\n", ""), Some(1));
+    assert_eq!(first_unformatted_line("a = 1;\n\n", "a = 1;\n"), Some(2));
+}
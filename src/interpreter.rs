@@ -1,3 +1,6 @@
+use super::csv::{self, Table};
+use super::error::EvalError;
+use super::trace::{self, Category};
 use super::types::*;
 
 use std::clone::Clone;
@@ -8,76 +11,346 @@ use std::fmt::Debug;
 pub struct Memory {
     instructions: Vec<Vec<AstNode>>,
     pub memory: HashMap<String, DimensionedValue>,
+    /// the csv files `csv{column}` reads from
+    tables: Vec<Table>,
+}
+
+/// Every csv column an expression reads, with where it is read
+fn csv_columns(expression: &AstNode) -> Vec<(&str, Span)> {
+    match expression {
+        AstNode::Csv { column, span, .. } => vec![(column, *span)],
+        AstNode::Expression { lhs, rhs, .. } => {
+            let mut columns = csv_columns(lhs);
+            columns.extend(csv_columns(rhs));
+            columns
+        }
+        AstNode::Conversion { expr, .. } => csv_columns(expr),
+        _ => vec![],
+    }
+}
+
+impl Memory {
+    /// The same memory, reading `csv{column}` from `tables`
+    pub fn with_tables(self, tables: Vec<Table>) -> Memory {
+        Memory { tables, ..self }
+    }
+
+    /// Forget every variable and statement, keeping the csv files
+    pub fn clear(&mut self) {
+        self.instructions.clear();
+        self.memory.clear();
+    }
+
+    /// Csv columns used in one expression are combined row by row, so they
+    /// must have as many rows as each other
+    fn check_row_counts(&self, expression: &AstNode) -> Result<(), EvalError> {
+        let mut first: Option<(String, usize)> = None;
+        for (column, span) in csv_columns(expression) {
+            let rows = csv::find_table(&self.tables, column, span)?.row_count();
+            match &first {
+                None => first = Some((column.to_string(), rows)),
+                Some((_, first_rows)) if *first_rows == rows => {}
+                Some(first) => {
+                    return Err(EvalError::RowCountMismatch {
+                        lhs: first.clone(),
+                        rhs: (column.to_string(), rows),
+                        span,
+                    })
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The quantity an expression evaluates to, worked out from units and the
+    /// exponents of powers. None when that depends on a value, like a power of
+    /// a variable
+    fn check_expression(
+        &self,
+        expression: &AstNode,
+        quantities: &HashMap<String, Option<Quantity>>,
+    ) -> Result<Option<Quantity>, EvalError> {
+        match expression {
+            AstNode::Name(name, span) => match quantities.get(name) {
+                Some(quantity) => Ok(*quantity),
+                None => Err(EvalError::UndefinedVariable {
+                    name: name.clone(),
+                    span: *span,
+                }),
+            },
+            AstNode::Double { unit, .. } | AstNode::Vector { unit, .. } => Ok(Some(unit.quantity)),
+            AstNode::Csv { column, unit, span } => {
+                csv::column(&self.tables, column, *span)?;
+                Ok(Some(unit.quantity))
+            }
+            AstNode::Expression {
+                operation,
+                lhs,
+                rhs,
+                span,
+            } => {
+                let lhs_quantity = self.check_expression(lhs, quantities)?;
+                let rhs_quantity = self.check_expression(rhs, quantities)?;
+
+                let result = match (operation, lhs_quantity, rhs_quantity) {
+                    // the unit of a power depends on the value of its exponent,
+                    // so only the exponent is evaluated. When that fails, on a
+                    // variable or 1 / 0, the unit is unknown and running the
+                    // program reports why
+                    (BinaryOperation::Power, Some(base), _) => match self.evaluate(*rhs.clone()) {
+                        Ok(exponent) => DimensionedValue {
+                            value: Value::Float(1.0),
+                            unit: Unit {
+                                unit: UnitIdentity::Base(1.0),
+                                quantity: base,
+                            },
+                        }
+                        .pow(exponent)
+                        .map(|power| Some(power.unit.quantity)),
+                        Err(_) if base.is_dimensionless() => Ok(Some(base)),
+                        Err(_) => Ok(None),
+                    },
+                    (BinaryOperation::Power, None, _) => Ok(None),
+                    (_, None, None) => Ok(None),
+                    (BinaryOperation::Add, Some(lhs), Some(rhs)) => (lhs + rhs).map(Some),
+                    (BinaryOperation::Subtract, Some(lhs), Some(rhs)) => (lhs - rhs).map(Some),
+                    (BinaryOperation::Add | BinaryOperation::Subtract, known, None)
+                    | (BinaryOperation::Add | BinaryOperation::Subtract, None, known) => Ok(known),
+                    (BinaryOperation::Multiply, Some(lhs), Some(rhs)) => (lhs * rhs).map(Some),
+                    (BinaryOperation::Divide, Some(lhs), Some(rhs)) => (lhs / rhs).map(Some),
+                    (BinaryOperation::Multiply | BinaryOperation::Divide, _, _) => Ok(None),
+                };
+                result.map_err(|error| error.at(*span))
+            }
+            AstNode::Conversion { expr, unit, span } => {
+                match self.check_expression(expr, quantities)? {
+                    Some(quantity) if quantity != unit.quantity => {
+                        Err(EvalError::ConversionMismatch {
+                            from: quantity,
+                            to: unit.quantity,
+                            span: *span,
+                        })
+                    }
+                    _ => Ok(Some(unit.quantity)),
+                }
+            }
+            // the parser only nests expressions in expressions, never statements
+            AstNode::Variable { .. } | AstNode::Print(_) => {
+                unreachable!("a statement inside an expression: {:?}", expression)
+            }
+        }
+    }
+
+    /// Evaluate one assignment and store its value
+    fn store(&mut self, variable: &AstNode) -> Result<(), EvalError> {
+        // the parser only produces assignments to a name as statements
+        let AstNode::Variable {
+            name,
+            annotation,
+            expr: expression,
+            ..
+        } = variable
+        else {
+            unreachable!("a statement that is not an assignment: {:?}", variable);
+        };
+        let AstNode::Name(name, span) = name.as_ref() else {
+            unreachable!("an assignment to something other than a name: {:?}", name);
+        };
+        let (name, span) = (name.clone(), *span);
+
+        self.check_row_counts(expression)?;
+        let united_value = self.evaluate(*expression.clone())?;
+
+        // a declared unit or dimension is checked before anything is stored
+        if let Some(annotation) = annotation {
+            if annotation.quantity() != united_value.unit.quantity {
+                return Err(EvalError::AnnotationMismatch {
+                    name,
+                    declared: annotation.quantity(),
+                    found: united_value.unit.quantity,
+                    span,
+                });
+            }
+        }
+
+        trace::event(
+            Category::Eval,
+            "store",
+            &[("name", &name), ("value", &united_value)],
+        );
+        self.memory.insert(name, united_value);
+
+        Ok(())
+    }
+
+    /// Run more statements after the ones already run, keeping every variable
+    /// so far. Statements before a failing one stay run
+    pub fn execute(&mut self, statements: Vec<AstNode>) -> Result<(), EvalError> {
+        for statement in statements {
+            self.store(&statement)?;
+            self.instructions.push(vec![statement]);
+        }
+
+        Ok(())
+    }
+
+    /// Run statements like `execute`, but when one fails undo the ones before
+    /// it too, so either all of them run or none of them do
+    pub fn execute_all(&mut self, statements: Vec<AstNode>) -> Result<(), EvalError> {
+        let (instructions, memory) = (self.instructions.len(), self.memory.clone());
+        let result = self.execute(statements);
+        if result.is_err() {
+            self.instructions.truncate(instructions);
+            self.memory = memory;
+        }
+        result
+    }
+
+    /// The statement that last assigned `name`
+    fn assignment(&self, name: &str) -> Option<&AstNode> {
+        self.instructions.iter().flatten().rev().find(|statement| {
+            matches!(statement, AstNode::Variable { name: variable, .. }
+                if matches!(variable.as_ref(), AstNode::Name(variable, _) if variable == name))
+        })
+    }
+
+    /// The expression `name` was last assigned
+    pub fn expression(&self, name: &str) -> Option<&AstNode> {
+        match self.assignment(name)? {
+            AstNode::Variable { expr, .. } => Some(expr.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The doc comment of the statement that last assigned `name`
+    pub fn doc(&self, name: &str) -> Option<&str> {
+        match self.assignment(name)? {
+            AstNode::Variable { doc, .. } => doc.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Every variable with its value, in the order they were first assigned
+    pub fn variables(&self) -> Vec<(&str, &DimensionedValue)> {
+        let mut names: Vec<&str> = vec![];
+        for statement in self.instructions.iter().flatten() {
+            if let AstNode::Variable { name, .. } = statement {
+                if let AstNode::Name(name, _) = name.as_ref() {
+                    if !names.contains(&name.as_str()) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        names
+            .into_iter()
+            .filter_map(|name| self.memory.get(name).map(|value| (name, value)))
+            .collect()
+    }
 }
 
 pub trait Interpreter {
     fn new(instructions: Vec<Vec<AstNode>>) -> Self;
-    fn run(&mut self) -> ();
-    fn evaluate(&self, expression: AstNode) -> DimensionedValue;
+    fn run(&mut self) -> Result<(), EvalError>;
+    fn check(&self) -> Result<(), EvalError>;
+    fn evaluate(&self, expression: AstNode) -> Result<DimensionedValue, EvalError>;
     fn evaluate_expression(
         &self,
         operation: BinaryOperation,
         lhs: Box<AstNode>,
         rhs: Box<AstNode>,
-    ) -> DimensionedValue;
+        span: Span,
+    ) -> Result<DimensionedValue, EvalError>;
 }
 
 impl Interpreter for Memory {
     fn new(instructions: Vec<Vec<AstNode>>) -> Self {
         Memory {
-            instructions: instructions,
+            instructions,
             memory: HashMap::new(),
+            tables: vec![],
         }
     }
 
-    fn run(&mut self) -> () {
-        for line in &self.instructions {
-            for variable in line {
-                println!("\nCalculating {:#?}", variable.clone());
-                let name = match variable {
-                    AstNode::Variable { name, expr: _expr } => match *name.clone() {
-                        AstNode::Name(name) => name,
-                        _ => panic!("Variable name should be of type AstNode::Name"),
-                    },
-                    _ => panic!("Variable should be of type AstNode::Variable"),
-                };
+    /// Evaluate every statement in order, stopping at the first one that fails
+    fn run(&mut self) -> Result<(), EvalError> {
+        for variable in self.instructions.clone().iter().flatten() {
+            self.store(variable)?;
+        }
 
-                let united_value = match variable {
-                    AstNode::Variable {
-                        name: _name,
-                        expr: expression,
-                    } => self.evaluate(*expression.clone()),
-                    _ => panic!("Variable should be of type AstNode::Variable"),
-                };
+        Ok(())
+    }
 
-                println!(
-                    "\nStoring result {:#?} = {:#?}",
-                    name.clone(),
-                    united_value.clone()
-                );
-                self.memory.insert(name, united_value);
-                println!("=================================\n\n");
+    /// Work out the quantity of every statement without evaluating it, so
+    /// dimension errors are found before any values are needed
+    fn check(&self) -> Result<(), EvalError> {
+        let mut quantities: HashMap<String, Option<Quantity>> = HashMap::new();
+        for statement in self.instructions.iter().flatten() {
+            let AstNode::Variable {
+                name,
+                annotation,
+                expr,
+                ..
+            } = statement
+            else {
+                // the parser only produces assignments to a name as statements
+                unreachable!("a statement that is not an assignment: {:?}", statement);
+            };
+            let AstNode::Name(name, span) = name.as_ref() else {
+                unreachable!("an assignment to something other than a name: {:?}", name);
+            };
+
+            self.check_row_counts(expr)?;
+            let quantity = self.check_expression(expr, &quantities)?;
+            let declared = annotation.as_ref().map(|annotation| annotation.quantity());
+            if let (Some(declared), Some(found)) = (declared, quantity) {
+                if declared != found {
+                    return Err(EvalError::AnnotationMismatch {
+                        name: name.clone(),
+                        declared,
+                        found,
+                        span: *span,
+                    });
+                }
             }
+            quantities.insert(name.clone(), declared.or(quantity));
         }
+
+        Ok(())
     }
 
-    fn evaluate(&self, expression: AstNode) -> DimensionedValue {
+    fn evaluate(&self, expression: AstNode) -> Result<DimensionedValue, EvalError> {
         match expression {
-            AstNode::Name(name) => self.memory[&name].clone(),
-            AstNode::Double { value, unit } => DimensionedValue {
-                value: value,
-                unit: unit,
-            },
-            AstNode::Vector { value, unit } => DimensionedValue {
-                value: value,
-                unit: unit,
+            AstNode::Name(name, span) => match self.memory.get(&name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(EvalError::UndefinedVariable { name, span }),
             },
+            AstNode::Double { value, unit } => Ok(DimensionedValue { value, unit }),
+            AstNode::Vector { value, unit } => Ok(DimensionedValue { value, unit }),
+            AstNode::Csv { column, unit, span } => Ok(DimensionedValue {
+                value: Value::Vec(csv::column(&self.tables, &column, span)?),
+                unit,
+            }),
             AstNode::Expression {
                 operation,
                 lhs,
                 rhs,
-            } => self.evaluate_expression(operation, lhs, rhs),
-            _ => panic!("1 Expression should be of type AstNode::Expression, AstNode::Double, AstNode::Vector, or AstNode::Name, found: {:#?}", expression),
+                span,
+            } => self.evaluate_expression(operation, lhs, rhs, span),
+            AstNode::Conversion { expr, unit, span } => {
+                let value = self.evaluate(*expr)?;
+                trace::event(
+                    Category::Eval,
+                    "convert",
+                    &[("value", &value), ("to", &unit)],
+                );
+                value.convert_to(unit).map_err(|error| error.at(span))
+            }
+            // the parser only nests expressions in expressions, never statements
+            AstNode::Variable { .. } | AstNode::Print(_) => {
+                unreachable!("a statement inside an expression: {:?}", expression)
+            }
         }
     }
 
@@ -86,80 +359,378 @@ impl Interpreter for Memory {
         operation: BinaryOperation,
         lhs: Box<AstNode>,
         rhs: Box<AstNode>,
-    ) -> DimensionedValue {
-        let lhs_value = match *lhs {
-            AstNode::Name(name) => self.memory[&name].clone(),
-            AstNode::Double { value, unit } => DimensionedValue {
-                value: value,
-                unit: unit,
-            },
-            AstNode::Vector { value, unit } => DimensionedValue {
-                value: value,
-                unit: unit,
-            },
-            AstNode::Expression {
-                operation,
-                lhs,
-                rhs,
-            } => self.evaluate_expression(operation, lhs, rhs),
-            _ => panic!("2 Expression should be of type AstNode::Expression, AstNode::Double, AstNode::Vector, or AstNode::Name, found: {:#?}", lhs),
-        };
+        span: Span,
+    ) -> Result<DimensionedValue, EvalError> {
+        let lhs_value = self.evaluate(*lhs)?;
+        let rhs_value = self.evaluate(*rhs)?;
+        // the operands are moved into the operation, keep them only when traced
+        let operands = (trace::is_enabled(Category::Eval) || trace::is_enabled(Category::Units))
+            .then(|| (lhs_value.clone(), rhs_value.clone()));
+        let op = operation.symbol();
 
-        let rhs_value = match *rhs {
-            AstNode::Name(name) => self.memory[&name].clone(),
-            AstNode::Double { value, unit } => DimensionedValue {
-                value: value,
-                unit: unit,
-            },
-            AstNode::Vector { value, unit } => DimensionedValue {
-                value: value,
-                unit: unit,
-            },
-            AstNode::Expression {
-                operation,
-                lhs,
-                rhs,
-            } => self.evaluate_expression(operation, lhs, rhs),
-            _ => panic!("3 Expression should be of type AstNode::Expression, AstNode::Double, AstNode::Vector, or AstNode::Name, found: {:#?}", rhs),
-        };
-
-        match operation {
+        // errors from the operands already point at them, errors from the
+        // operation itself point at this expression
+        let result = match operation {
             BinaryOperation::Add => lhs_value + rhs_value,
             BinaryOperation::Subtract => lhs_value - rhs_value,
             BinaryOperation::Multiply => lhs_value * rhs_value,
             BinaryOperation::Divide => lhs_value / rhs_value,
+            BinaryOperation::Power => lhs_value.pow(rhs_value),
+        };
+
+        if let (Some((lhs, rhs)), Ok(value)) = (&operands, &result) {
+            trace::event(
+                Category::Eval,
+                "operation",
+                &[("op", &op), ("lhs", lhs), ("rhs", rhs), ("result", value)],
+            );
+            trace::event(
+                Category::Units,
+                "derive",
+                &[
+                    ("op", &op),
+                    ("lhs", &lhs.unit),
+                    ("rhs", &rhs.unit),
+                    ("result", &value.unit),
+                ],
+            );
         }
+        result.map_err(|error| error.at(span))
     }
 }
 
 #[test]
 fn test_interpreter() {
-    let i: Memory = Interpreter::new(vec![vec![AstNode::Variable {
-        name: Box::new(AstNode::Name("var".to_string())),
+    let mut i: Memory = Interpreter::new(vec![vec![AstNode::Variable {
+        name: Box::new(AstNode::Name("var".to_string(), Span::default())),
+        annotation: None,
+        doc: None,
         expr: Box::new(AstNode::Expression {
             operation: BinaryOperation::Divide,
             lhs: Box::new(AstNode::Double {
                 value: Value::Float(2.0),
-                unit: Unit::Length {
-                    unit: Unit {
-                        unit: UnitIdentity::Meter,
-                        conversion_factor: 1.0,
-                    },
-                    power: 1,
+                unit: Unit {
+                    unit: UnitIdentity::Meter(1.0),
+                    quantity: Quantity::base(BaseDimension::Length, 1),
                 },
             }),
             rhs: Box::new(AstNode::Double {
                 value: Value::Float(2.0),
-                unit: Unit::Length {
-                    unit: Unit {
-                        unit: UnitIdentity::Kilometer,
-                        conversion_factor: 1000.0,
-                    },
-                    power: 1,
+                unit: Unit {
+                    unit: UnitIdentity::Meter(1000.0),
+                    quantity: Quantity::base(BaseDimension::Length, 1),
                 },
             }),
+            span: Span::default(),
         }),
     }]]);
 
-    println!("{:#?}", i.memory);
+    i.run().unwrap();
+
+    assert_eq!(i.memory["var"].value, Value::Float(0.001));
+    assert!(i.memory["var"].unit.quantity.is_dimensionless());
+}
+
+#[test]
+fn test_interpreter_errors() {
+    use super::parser::parse_program;
+
+    let source = "a = 1[m];\nb = ((a * 2[m]) + c);";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert!(matches!(&error, EvalError::UndefinedVariable { name, .. } if name == "c"));
+    assert!(error
+        .render("test.r2", source)
+        .contains(" --> test.r2:2:19\n"));
+
+    let source = "a = 1[m];\nb = ((a * 2[m]) + a);";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert!(matches!(error, EvalError::DimensionMismatch { .. }));
+    assert!(error
+        .render("test.r2", source)
+        .contains(" --> test.r2:2:5\n"));
+
+    let source = "a = ([1 2 3][m] / (1[m] - 1[m]));";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert!(matches!(error, EvalError::DivisionByZero { .. }));
+    assert!(error
+        .render("test.r2", source)
+        .contains(" --> test.r2:1:5\n"));
+
+    let source = "a = ([1 2 3][m] + [1 2][m]);";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    assert!(matches!(
+        i.run(),
+        Err(EvalError::VectorLengthMismatch { lhs: 3, rhs: 2, .. })
+    ));
+
+    let source = "a = 1[m^2000000000] * 1[m^2000000000];";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert!(matches!(error, EvalError::ExponentOverflow { .. }));
+    assert!(error
+        .render("test.r2", source)
+        .contains(" --> test.r2:1:5\n"));
+}
+
+#[test]
+fn test_interpreter_precedence() {
+    use super::parser::parse_program;
+
+    let source = "a = 2[m] * 3[m] + 1[m^2];\nb = -a / 7[m^2] - 1;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    i.run().unwrap();
+
+    assert_eq!(i.memory["a"].value, Value::Float(7.0));
+    assert_eq!(
+        i.memory["a"].unit.quantity,
+        Quantity::base(BaseDimension::Length, 2)
+    );
+    assert_eq!(i.memory["b"].value, Value::Float(-2.0));
+}
+
+#[test]
+fn test_interpreter_power() {
+    use super::parser::parse_program;
+
+    let source = "a = (2[m])^3;\nb = [1 2 3][km]^2;\nc = (a * 2[m])^0.5;\nf = -3^2;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    i.run().unwrap();
+
+    let length = Quantity::base(BaseDimension::Length, 1);
+    assert_eq!(i.memory["a"].value, Value::Float(8.0));
+    assert_eq!(i.memory["a"].unit.quantity, length.powi(3).unwrap());
+    assert_eq!(i.memory["b"].value, Value::Vec(vec![1e6, 4e6, 9e6]));
+    assert_eq!(i.memory["b"].unit.quantity, length.powi(2).unwrap());
+    assert_eq!(i.memory["c"].value, Value::Float(4.0));
+    assert_eq!(i.memory["c"].unit.quantity, length.powi(2).unwrap());
+    assert_eq!(i.memory["f"].value, Value::Float(-9.0));
+
+    let source = "a = 1[m];\nb = a^0.5;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert!(matches!(error, EvalError::InvalidPower { .. }));
+    assert_eq!(
+        error.render("test.r2", source),
+        "error: cannot raise `m` to the power 0.5, the result has a fractional unit\n --> test.r2:2:5\n  |\n2 | b = a^0.5;\n  |     ^"
+    );
+
+    let source = "a = 2[m]^3000000000;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    assert_eq!(
+        i.run().unwrap_err().to_string(),
+        "cannot raise `m` to the power 3000000000, the exponents of the unit are too large"
+    );
+
+    let source = "a = 2^1[m];";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    assert!(matches!(
+        i.run(),
+        Err(EvalError::DimensionedExponent { .. })
+    ));
+}
+
+#[test]
+fn test_interpreter_dimensionless() {
+    use super::parser::parse_program;
+
+    let source =
+        "rate = 15%;\nwage = 2000[USD/month];\nraise = wage * rate;\nratio = 1[km] / 1[m] + 1;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    i.run().unwrap();
+
+    assert_eq!(i.memory["rate"].value, Value::Float(0.15));
+    assert_eq!(
+        i.memory["raise"].unit.quantity,
+        i.memory["wage"].unit.quantity
+    );
+    assert_eq!(i.memory["ratio"].value, Value::Float(1001.0));
+    assert!(i.memory["ratio"].unit.quantity.is_dimensionless());
+
+    let source = "a = 1[m] + 1;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "cannot add `m` and a dimensionless value"
+    );
+}
+
+#[test]
+fn test_interpreter_conversion() {
+    use super::parser::parse_program;
+
+    let source = "distance = 150[km];\nspeed = 60[km/h];\ntime = distance / speed as [h];\ndoubled = (time in [min]) * 2;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    i.run().unwrap();
+
+    assert_eq!(i.memory["time"].value, Value::Float(2.5));
+    assert_eq!(i.memory["time"].to_string(), "2.5 h");
+    assert_eq!(
+        i.variables()
+            .into_iter()
+            .map(|(name, value)| format!("{} = {}", name, value))
+            .collect::<Vec<String>>(),
+        vec![
+            "distance = 150 km",
+            "speed = 60 km/h",
+            "time = 2.5 h",
+            "doubled = 18000 s"
+        ]
+    );
+    assert_eq!(i.memory["time"].unit.unit, UnitIdentity::Hour(3600.0));
+    // arithmetic on a converted value goes back to base units
+    assert_eq!(i.memory["doubled"].value, Value::Float(18000.0));
+
+    let source = "a = 1[m];\nb = a * 2 as [s];";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert!(matches!(error, EvalError::ConversionMismatch { .. }));
+    assert_eq!(
+        error.render("test.r2", source),
+        "error: cannot convert `m` to `s`\n --> test.r2:2:11\n  |\n2 | b = a * 2 as [s];\n  |           ^"
+    );
+}
+
+#[test]
+fn test_interpreter_annotations() {
+    use super::parser::parse_program;
+
+    let source = "wage[USD/month] = 2000[USD/month];\nabsence[time] = 3[day];\nbenefit[currency] = wage * absence;\nexposure[currency*time] = benefit * absence;\nshare[dimensionless] = 15%;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    i.run().unwrap();
+    assert_eq!(i.memory.len(), 5);
+
+    let source = "wage[USD/month] = 2000[USD];\nb = 1;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    let error = i.run().unwrap_err();
+    assert!(matches!(&error, EvalError::AnnotationMismatch { name, .. } if name == "wage"));
+    assert_eq!(
+        error.render("test.r2", source),
+        "error: `wage` is declared as `USD/s` but evaluates to `USD`\n --> test.r2:1:1\n  |\n1 | wage[USD/month] = 2000[USD];\n  | ^"
+    );
+    // nothing after the failed assignment runs, and it is not stored
+    assert!(i.memory.is_empty());
+
+    let source = "a = 2[m];\nb[currency/time] = a * 2;";
+    let mut i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    assert!(matches!(i.run(), Err(EvalError::AnnotationMismatch { .. })));
+}
+
+#[test]
+fn test_check() {
+    use super::parser::parse_program;
+
+    let check = |source: &str| {
+        let i: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+        i.check()
+    };
+
+    assert_eq!(
+        check("a = 2[m];\nb = (a * 3[m])^0.5 + 1[km] as [m];"),
+        Ok(())
+    );
+    // nothing is evaluated, so values that would fail are fine
+    assert_eq!(check("a = 1[m] / (1[m] - 1[m]);"), Ok(()));
+    // exponents are evaluated, as their value decides the unit
+    let error = check("a = 2[m]^(4 / 2);\nb = a + 1[s];").unwrap_err();
+    assert!(matches!(error, EvalError::DimensionMismatch { .. }));
+    // but an exponent that fails to evaluate leaves the unit unknown
+    assert_eq!(check("a = 2[m]^(1 / 0);"), Ok(()));
+    // as do powers of variables, which are only known when evaluated
+    assert_eq!(check("n = 2;\na = 2[m]^n;\nb = a + 1[s];"), Ok(()));
+    assert_eq!(
+        check("n = 4[m^2];\na = 2[m]^(n / 1[m^2]) + 1[m^4];"),
+        Ok(())
+    );
+    // and a declared unit is trusted
+    assert_eq!(check("n = 2;\na[m^2] = 2[m]^n;\nb = a + 1[m^2];"), Ok(()));
+
+    let error = check("a = 1[m];\nb = a + 1[s];").unwrap_err();
+    assert!(matches!(error, EvalError::DimensionMismatch { .. }));
+    assert_eq!(error.span().from_end, "a + 1[s];".len());
+
+    assert!(matches!(
+        check("a = 1[m] as [s];"),
+        Err(EvalError::ConversionMismatch { .. })
+    ));
+    assert!(matches!(
+        check("a[time] = 1[m];"),
+        Err(EvalError::AnnotationMismatch { .. })
+    ));
+    assert!(matches!(
+        check("a = 1[m]^0.5;"),
+        Err(EvalError::InvalidPower { .. })
+    ));
+    assert!(matches!(
+        check("a = b;"),
+        Err(EvalError::UndefinedVariable { .. })
+    ));
+}
+
+#[test]
+fn test_execute() {
+    use super::parser::parse_program;
+
+    let mut i: Memory = Interpreter::new(vec![]);
+    for line in parse_program("test.r2", "a = 1[km];\nb = a * 2;").unwrap() {
+        i.execute(line).unwrap();
+    }
+    let statements = parse_program("test.r2", "c = b + 1[m]; f = c + 1[s]; e = 1;")
+        .unwrap()
+        .remove(0);
+    assert!(i.execute(statements).is_err());
+
+    assert_eq!(
+        i.variables()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<&str>>(),
+        vec!["a", "b", "c"]
+    );
+    assert_eq!(i.memory["c"].value, Value::Float(2001.0));
+
+    // unless every statement has to run
+    let statements = parse_program("test.r2", "c = 1; x = 1; e = 1[m] + 1[s];")
+        .unwrap()
+        .remove(0);
+    assert!(i.execute_all(statements).is_err());
+    assert_eq!(i.variables().len(), 3);
+    assert_eq!(i.memory["c"].value, Value::Float(2001.0));
+    assert_eq!(i.expression("c").unwrap().to_string(), "b + 1[m]");
+}
+
+#[test]
+fn test_interpreter_csv() {
+    use super::parser::parse_program;
+
+    let tables = vec![
+        Table::parse("sides.csv", "height_of_side,width_of_base\n1,200\n2,300\n").unwrap(),
+        Table::parse("costs.csv", "cost\n10\n20\n30\n").unwrap(),
+    ];
+    let memory = |source: &str| -> Memory {
+        let memory: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+        memory.with_tables(tables.clone())
+    };
+
+    let mut i = memory("a = csv{height_of_side[km]};\nb = csv{width_of_base[m]};\nc = a * b;");
+    i.check().unwrap();
+    i.run().unwrap();
+    assert_eq!(i.memory["c"].to_string(), "[200000, 600000] m²");
+
+    let error = memory("a = csv{height_of_side[km]} * csv{cost[USD]};")
+        .run()
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "csv columns `height_of_side` and `cost` are used together but have 2 and 3 rows"
+    );
+    assert!(memory("a = csv{height_of_side} * csv{cost};")
+        .check()
+        .is_err());
+
+    let error = memory("a = csv{heigth};").check().unwrap_err();
+    assert!(matches!(error, EvalError::UnknownColumn { .. }));
 }
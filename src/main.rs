@@ -1,55 +1,209 @@
-use std::fs::File;
-use std::io::Read;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::process;
 
+pub mod cli;
+pub mod csv;
+pub mod error;
+pub mod formatter;
 pub mod interpreter;
+pub mod output;
 pub mod parser;
+pub mod repl;
+pub mod trace;
 pub mod types;
+pub mod units;
 
+use cli::{exit_code, Command};
+use csv::Table;
 use interpreter::{Interpreter, Memory};
 use parser::*;
 
-/// (average_wage_per_unit_calendar_time * (avoided_days_of_lost_due_to_anxiety + avoided_days_of_lost_due_to_depression))
-///  * ((proportion_proxy_benefit_attributed_to_wellbeing_anxiety + proportion_proxy_benefit_attributed_to_wellbeing_depression) / 2)
-///  * imp_employee_time_in_mental_wellbeing_programmes
-
-/// [inputs]
-/// average_wage = 12[USD]/4[month]
-/// avoided_days = 3[days]
-///
-/// [calculation]
-/// employee_mental_wellbeing_benefit[currency*time] = (average_wage_per_unit_calendar_time[currency/time] * (avoided_days_of_lost_due_to_anxiety[time] + avoided_days_of_lost_due_to_depression[time]))
-///  * ((proportion_proxy_benefit_attributed_to_wellbeing_anxiety[float] + proportion_proxy_benefit_attributed_to_wellbeing_depression[float]) / 2)
-///  * imp_employee_time_in_mental_wellbeing_programmes[time]
-
-/// use fuel
-///
-///
-/// TODO: variables need units, otherwise can do valid but unintended calculations
-
-fn main() -> () {
-    let mut test_file = File::open("./test.r2").unwrap();
-    let mut input_file_contents = String::new();
-    test_file.read_to_string(&mut input_file_contents).unwrap();
-
-    let mut program = vec![];
-    for line in input_file_contents.lines().by_ref() {
-        if line.is_empty() {
-            continue;
+// (average_wage_per_unit_calendar_time * (avoided_days_of_lost_due_to_anxiety + avoided_days_of_lost_due_to_depression))
+//  * ((proportion_proxy_benefit_attributed_to_wellbeing_anxiety + proportion_proxy_benefit_attributed_to_wellbeing_depression) / 2)
+//  * imp_employee_time_in_mental_wellbeing_programmes
+
+// [inputs]
+// average_wage = 12[USD]/4[month]
+// avoided_days = 3[days]
+//
+// [calculation]
+// employee_mental_wellbeing_benefit[currency*time] = (average_wage_per_unit_calendar_time[currency/time] * (avoided_days_of_lost_due_to_anxiety[time] + avoided_days_of_lost_due_to_depression[time]))
+//  * ((proportion_proxy_benefit_attributed_to_wellbeing_anxiety[float] + proportion_proxy_benefit_attributed_to_wellbeing_depression[float]) / 2)
+//  * imp_employee_time_in_mental_wellbeing_programmes[time]
+
+// use fuel
+
+/// The contents of `file`, or of stdin when it is `-`
+fn read_source(file: &str) -> io::Result<String> {
+    let mut source = String::new();
+    match file {
+        "-" => io::stdin().read_to_string(&mut source)?,
+        file => File::open(file)?.read_to_string(&mut source)?,
+    };
+    Ok(source)
+}
+
+/// Parse every csv file, reporting why one could not be read or parsed as
+/// an exit code
+fn load_tables(files: &[String]) -> Result<Vec<Table>, i32> {
+    files
+        .iter()
+        .map(|file| {
+            let source = fs::read_to_string(file).map_err(|error| {
+                eprintln!("error: cannot read {}: {}", file, error);
+                exit_code::NO_INPUT
+            })?;
+            Table::parse(file, &source).map_err(|error| {
+                eprintln!("{}", error);
+                exit_code::BAD_DATA
+            })
+        })
+        .collect()
+}
+
+/// Parse `file`, reporting why it could not be read or parsed as an exit code
+fn load(file: &str, tables: Vec<Table>) -> Result<(String, Memory), i32> {
+    let source = read_source(file).map_err(|error| {
+        eprintln!("error: cannot read {}: {}", cli::display_name(file), error);
+        exit_code::NO_INPUT
+    })?;
+    let program = parse_program(cli::display_name(file), &source).map_err(|error| {
+        eprintln!("{}", error);
+        exit_code::PARSE_ERROR
+    })?;
+
+    let memory: Memory = Interpreter::new(program);
+    Ok((source, memory.with_tables(tables)))
+}
+
+fn run(file: &str, tables: Vec<Table>, options: &cli::Options) -> Result<(), i32> {
+    let (source, mut memory) = load(file, tables)?;
+    memory.run().map_err(|error| {
+        eprintln!("{}", error.render(cli::display_name(file), &source));
+        exit_code::EVAL_ERROR
+    })?;
+    if options.derived_units {
+        for value in memory.memory.values_mut() {
+            *value = value.in_derived_units();
         }
-        let (input, parsed_line) = parse_line(line).unwrap();
-        if !input.is_empty() {
-            eprintln!("parsing error, input remaining {:?}", input);
-            process::exit(1);
+    }
+
+    print!("{}", memory.export(options.output, !options.no_expressions));
+    Ok(())
+}
+
+fn check(file: &str, tables: Vec<Table>) -> Result<(), i32> {
+    let (source, memory) = load(file, tables)?;
+    memory.check().map_err(|error| {
+        eprintln!("{}", error.render(cli::display_name(file), &source));
+        exit_code::EVAL_ERROR
+    })?;
+
+    println!("{}: ok", cli::display_name(file));
+    Ok(())
+}
+
+/// Rewrite `file` in the canonical format, or with `check` only report
+/// whether it is. Formatting stdin writes the result to stdout
+fn format(file: &str, check: bool) -> Result<(), i32> {
+    let file_name = cli::display_name(file);
+    let source = read_source(file).map_err(|error| {
+        eprintln!("error: cannot read {}: {}", file_name, error);
+        exit_code::NO_INPUT
+    })?;
+    let formatted = formatter::format_source(file_name, &source).map_err(|error| {
+        eprintln!("{}", error);
+        exit_code::PARSE_ERROR
+    })?;
+
+    match formatter::first_unformatted_line(&source, &formatted) {
+        Some(line) if check => {
+            eprintln!(
+                "error: {} is not formatted, run `r2engine fmt {}`\n --> {}:{}",
+                file_name, file, file_name, line
+            );
+            Err(exit_code::UNFORMATTED)
         }
-        program.push(parsed_line);
+        _ if check => Ok(()),
+        _ if file == "-" => {
+            print!("{}", formatted);
+            Ok(())
+        }
+        Some(_) => fs::write(file, formatted).map_err(|error| {
+            eprintln!("error: cannot write {}: {}", file, error);
+            exit_code::CANNOT_WRITE
+        }),
+        None => Ok(()),
     }
+}
 
-    let mut i: Memory = Interpreter::new(program.clone());
+fn eval(source: &str, tables: Vec<Table>, derived_units: bool) -> Result<(), i32> {
+    let file_name = "<eval>";
+    let expression = parse_single_expression(file_name, source).map_err(|error| {
+        eprintln!("{}", error);
+        exit_code::PARSE_ERROR
+    })?;
+    let memory: Memory = Interpreter::new(vec![]);
+    let memory = memory.with_tables(tables);
+    let value = memory.evaluate(expression).map_err(|error| {
+        eprintln!("{}", error.render(file_name, source));
+        exit_code::EVAL_ERROR
+    })?;
 
-    i.run();
+    if derived_units {
+        println!("{}", value.in_derived_units());
+    } else {
+        println!("{}", value);
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let (options, command) = match cli::take_options(&mut args)
+        .and_then(|options| Ok((options, cli::parse_args(&args)?)))
+    {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(exit_code::USAGE);
+        }
+    };
 
-    println!("{:#?}", i.memory);
+    options.trace.iter().copied().for_each(trace::enable);
+    let tables = match load_tables(&options.csv_files) {
+        Ok(tables) => tables,
+        Err(code) => process::exit(code),
+    };
+
+    let result = match command {
+        Command::Run { file } => run(&file, tables, &options),
+        Command::Check { file } => check(&file, tables),
+        Command::Eval { expression } => eval(&expression, tables, options.derived_units),
+        Command::Fmt { file, check } => format(&file, check),
+        Command::Repl => {
+            let history_file = env::var_os("HOME")
+                .map(|home| std::path::PathBuf::from(home).join(".r2engine_history"));
+            let mut repl = repl::Repl::new(history_file)
+                .with_tables(tables)
+                .with_derived_units(options.derived_units);
+            println!("r2engine, :help for commands");
+            repl.run(io::stdin().lock(), &mut io::stdout())
+                .map_err(|error| {
+                    eprintln!("error: {}", error);
+                    exit_code::NO_INPUT
+                })
+        }
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        }
+    };
 
-    ()
+    process::exit(match result {
+        Ok(()) => exit_code::SUCCESS,
+        Err(code) => code,
+    });
 }
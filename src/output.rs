@@ -0,0 +1,251 @@
+use super::interpreter::Memory;
+use super::types::{BaseDimension, DimensionedValue, Value};
+
+/// How `run` writes the variables of a program
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// `name = value unit`, one variable per line, for people
+    #[default]
+    Text,
+    /// an object with a `variables` array, for other programs
+    Json,
+    /// one row per value, a vector has a row for each element
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Every digit needed to read the number back. JSON has no infinity or NaN
+fn json_number(number: f64) -> String {
+    if number.is_finite() {
+        number.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+/// A csv cell, quoted when it holds a comma, quote or newline
+fn csv_cell(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// A variable as it is exported
+struct Record<'a> {
+    name: &'a str,
+    value: DimensionedValue,
+    /// the expression and doc comment of the variable, when they are exported
+    source: Option<(String, Option<&'a str>)>,
+}
+
+fn to_json(records: &[Record]) -> String {
+    let records: Vec<String> = records
+        .iter()
+        .map(
+            |Record {
+                 name,
+                 value,
+                 source,
+             }| {
+                let values = match &value.value {
+                    Value::Float(number) => json_number(*number),
+                    Value::Vec(numbers) => format!(
+                        "[{}]",
+                        numbers
+                            .iter()
+                            .map(|number| json_number(*number))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                };
+                let dimensions: Vec<String> = BaseDimension::ALL
+                    .iter()
+                    .map(|dimension| {
+                        format!(
+                            "{}: {}",
+                            json_string(dimension.name()),
+                            value.unit.quantity.exponent(*dimension)
+                        )
+                    })
+                    .collect();
+
+                let mut record = format!(
+                    "    {{\"name\": {}, \"value\": {}, \"unit\": {}, \"dimensions\": {{{}}}",
+                    json_string(name),
+                    values,
+                    json_string(&value.unit.symbol(true)),
+                    dimensions.join(", ")
+                );
+                if let Some((expression, doc)) = source {
+                    record.push_str(&format!(", \"expression\": {}", json_string(expression)));
+                    if let Some(doc) = doc {
+                        record.push_str(&format!(", \"doc\": {}", json_string(doc)));
+                    }
+                }
+                record.push('}');
+                record
+            },
+        )
+        .collect();
+
+    if records.is_empty() {
+        return "{\"variables\": []}\n".to_string();
+    }
+    format!("{{\"variables\": [\n{}\n]}}\n", records.join(",\n"))
+}
+
+fn to_csv(records: &[Record], expressions: bool) -> String {
+    let mut header = vec!["name", "index", "value", "unit"];
+    header.extend(BaseDimension::ALL.iter().map(|dimension| dimension.name()));
+    if expressions {
+        header.extend(["expression", "doc"]);
+    }
+    let mut csv = header.join(",");
+    csv.push('\n');
+
+    for Record {
+        name,
+        value,
+        source,
+    } in records
+    {
+        // a scalar has no index, so it can be told from a vector of one
+        let rows: Vec<(String, f64)> = match &value.value {
+            Value::Float(number) => vec![(String::new(), *number)],
+            Value::Vec(numbers) => numbers
+                .iter()
+                .enumerate()
+                .map(|(index, number)| (index.to_string(), *number))
+                .collect(),
+        };
+        for (index, number) in rows {
+            let mut row = vec![
+                csv_cell(name),
+                index,
+                number.to_string(),
+                csv_cell(&value.unit.symbol(true)),
+            ];
+            row.extend(
+                BaseDimension::ALL
+                    .iter()
+                    .map(|dimension| value.unit.quantity.exponent(*dimension).to_string()),
+            );
+            if let Some((expression, doc)) = source {
+                row.push(csv_cell(expression));
+                row.push(csv_cell(doc.unwrap_or_default()));
+            }
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+impl Memory {
+    /// Every variable in `format`, in the order they were first assigned.
+    /// Values are in the unit they print in, with the unit in ASCII and the
+    /// exponent of every base dimension. `expressions` adds the expression
+    /// each variable was last assigned and its `##` doc comment, to json and csv
+    pub fn export(&self, format: Format, expressions: bool) -> String {
+        let records: Vec<Record> = self
+            .variables()
+            .into_iter()
+            .map(|(name, value)| Record {
+                name,
+                value: value.displayed(),
+                source: self
+                    .expression(name)
+                    .filter(|_| expressions)
+                    .map(|expression| (expression.to_string(), self.doc(name))),
+            })
+            .collect();
+
+        match format {
+            Format::Text => records
+                .iter()
+                .map(|record| format!("{} = {}\n", record.name, record.value))
+                .collect(),
+            Format::Json => to_json(&records),
+            Format::Csv => to_csv(&records, expressions),
+        }
+    }
+}
+
+#[cfg(test)]
+fn run(source: &str) -> Memory {
+    use super::interpreter::Interpreter;
+    use super::parser::parse_program;
+
+    let mut memory: Memory = Interpreter::new(parse_program("test.r2", source).unwrap());
+    memory.run().unwrap();
+    memory
+}
+
+#[test]
+fn test_export_text() {
+    let memory = run("distance = 150[km];\nspeed = 60[km/h];\ntime = distance / speed as [h];");
+    assert_eq!(
+        memory.export(Format::Text, true),
+        "distance = 150 km\nspeed = 60 km/h\ntime = 2.5 h\n"
+    );
+}
+
+#[test]
+fn test_export_json() {
+    let memory = run("## the floor\narea = 2[km] * 3[m];\nsides = [1 2.5][m^2] / 2;");
+    assert_eq!(
+        memory.export(Format::Json, true),
+        "{\"variables\": [\n    \
+        {\"name\": \"area\", \"value\": 6000, \"unit\": \"m^2\", \"dimensions\": {\"length\": 2, \"mass\": 0, \"time\": 0, \"temperature\": 0, \"amount\": 0, \"current\": 0, \"luminosity\": 0, \"currency\": 0}, \"expression\": \"2[km] * 3[m]\", \"doc\": \"the floor\"},\n    \
+        {\"name\": \"sides\", \"value\": [0.5, 1.25], \"unit\": \"m^2\", \"dimensions\": {\"length\": 2, \"mass\": 0, \"time\": 0, \"temperature\": 0, \"amount\": 0, \"current\": 0, \"luminosity\": 0, \"currency\": 0}, \"expression\": \"[1 2.5][m^2] / 2\"}\n\
+        ]}\n"
+    );
+
+    assert!(!memory.export(Format::Json, false).contains("expression"));
+    assert_eq!(run("").export(Format::Json, true), "{\"variables\": []}\n");
+    assert_eq!(json_string("a \"b\"\n"), "\"a \\\"b\\\"\\n\"");
+    assert_eq!(json_number(f64::INFINITY), "null");
+}
+
+#[test]
+fn test_export_csv() {
+    let memory = run("rate = 12[USD] / 4[month] as [USD/month];\nsides = [1 2][m];");
+    assert_eq!(
+        memory.export(Format::Csv, false),
+        "name,index,value,unit,length,mass,time,temperature,amount,current,luminosity,currency\n\
+        rate,,3,USD/month,0,0,-1,0,0,0,0,1\n\
+        sides,0,1,m,1,0,0,0,0,0,0,0\n\
+        sides,1,2,m,1,0,0,0,0,0,0,0\n"
+    );
+    assert!(memory.export(Format::Csv, true).contains(
+        ",expression,doc\nrate,,3,USD/month,0,0,-1,0,0,0,0,1,12[USD] / 4[month] as [USD/month],\n"
+    ));
+    assert_eq!(csv_cell("a, \"b\""), "\"a, \"\"b\"\"\"");
+}
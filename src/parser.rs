@@ -1,340 +1,902 @@
 extern crate nom;
 
 use nom::branch::alt;
-use nom::bytes::complete::tag;
+use nom::bytes::complete::{tag, take_while, take_while1};
 use nom::character::complete::digit1;
-use nom::character::complete::{alpha1, char, space0};
+use nom::character::complete::{char, multispace1, not_line_ending, satisfy};
+use nom::combinator::{cut, map_res, not, opt, recognize};
+use nom::error::{context, ContextError, ErrorKind, FromExternalError};
 use nom::multi::{many0, many1};
-use nom::number::complete::double;
-use nom::sequence::{delimited, preceded, terminated};
+use nom::number::complete::recognize_float;
+use nom::sequence::{delimited, pair, preceded, terminated};
 use nom::IResult;
 
+use super::error::{position, EvalError, ParseError};
+use super::trace::{self, Category};
 use super::types::*;
+use super::units;
 
-fn parse_length(input: &str) -> IResult<&str, Unit> {
-    println!("reached parse_length {}", input.clone());
-
-    // TODO: none of this is very nice, differentiate unit families better
-
-    // https://docs.rs/nom/latest/nom/branch/fn.alt.html
-    println!("  parsing unit {}", input.clone());
-    let (input, _) = tag("[")(input)?;
-    // println!("  parsing unit {}", input.clone());
-    let (input, unit_alias) = alt((
-        tag("none"),
-        alt((tag("days"), tag("day"), tag("d"))),
-        tag("hours"),
-        tag("hour"),
-        tag("h"),
-        tag("minutes"),
-        tag("minute"),
-        tag("min"),
-        tag("seconds"),
-        tag("second"),
-        tag("s"),
-        tag("meters"),
-        tag("meter"),
-        tag("m"), // longest to shortest!!
-        tag("kilometers"),
-        tag("kilometer"),
-        tag("km"),
-        alt((tag("USD"), tag("$"), tag("GBP"), tag("£"))),
+/// The furthest point a parser got to, and what it expected there.
+/// `alt` keeps whichever branch got furthest, so the error points at the
+/// actual mistake rather than at the start of the statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError<'a> {
+    pub input: &'a str,
+    pub expected: Vec<String>,
+    pub message: Option<String>,
+}
+
+impl<'a> SyntaxError<'a> {
+    fn expected(input: &'a str, expected: String) -> SyntaxError<'a> {
+        SyntaxError {
+            input,
+            expected: vec![expected],
+            message: None,
+        }
+    }
+
+    /// Input that was recognised but is not valid, e.g. an unknown unit
+    fn invalid(input: &'a str, message: String) -> nom::Err<SyntaxError<'a>> {
+        nom::Err::Failure(SyntaxError {
+            input,
+            expected: vec![],
+            message: Some(message),
+        })
+    }
+}
+
+impl<'a> nom::error::ParseError<&'a str> for SyntaxError<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        SyntaxError {
+            input,
+            expected: vec![],
+            message: None,
+        }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(self, other: Self) -> Self {
+        match self.input.len().cmp(&other.input.len()) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal => {
+                let mut merged = self;
+                for expected in other.expected {
+                    if !merged.expected.contains(&expected) {
+                        merged.expected.push(expected);
+                    }
+                }
+                merged
+            }
+        }
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for SyntaxError<'a> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _error: E) -> Self {
+        nom::error::ParseError::from_error_kind(input, kind)
+    }
+}
+
+impl<'a> ContextError<&'a str> for SyntaxError<'a> {
+    /// a context names what was expected, as long as the parser failed
+    /// before consuming anything
+    fn add_context(input: &'a str, context: &'static str, other: Self) -> Self {
+        if other.input.len() == input.len() && other.message.is_none() {
+            SyntaxError::expected(input, context.to_string())
+        } else {
+            other
+        }
+    }
+}
+
+type ParseResult<'a, T> = IResult<&'a str, T, SyntaxError<'a>>;
+
+/// A literal token, reported as `token` when it is missing
+fn token<'a>(expected: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    move |input| {
+        tag(expected)(input).map_err(|error: nom::Err<SyntaxError>| {
+            error.map(|_| SyntaxError::expected(input, format!("`{}`", expected)))
+        })
+    }
+}
+
+/// `# to the end of the line`. `##` starts a doc comment, which is only
+/// kept before a statement
+fn line_comment(input: &str) -> ParseResult<'_, &str> {
+    recognize(pair(char('#'), not_line_ending))(input)
+}
+
+/// `/* anything, over any number of lines */`
+fn block_comment(input: &str) -> ParseResult<'_, &str> {
+    let (rest, _) = tag("/*")(input)?;
+    match rest.find("*/") {
+        Some(end) => Ok((&rest[end + 2..], &input[..end + 4])),
+        None => Err(SyntaxError::invalid(
+            input,
+            "unterminated block comment, expected `*/`".to_string(),
+        )),
+    }
+}
+
+/// Spaces, newlines and comments, which can go anywhere a space can
+fn whitespace0(input: &str) -> ParseResult<'_, &str> {
+    recognize(many0(alt((multispace1, line_comment, block_comment))))(input)
+}
+
+/// `parser` after any whitespace and comments, so every token may be
+/// spaced out or written right against the one before it
+fn lexeme<'a, O>(
+    parser: impl FnMut(&'a str) -> ParseResult<'a, O>,
+) -> impl FnMut(&'a str) -> ParseResult<'a, O> {
+    preceded(whitespace0, parser)
+}
+
+/// Whitespace and comments up to the next `##` doc comment
+fn whitespace_before_doc(input: &str) -> ParseResult<'_, &str> {
+    let plain_comment = recognize(pair(pair(char('#'), not(char('#'))), not_line_ending));
+    recognize(many0(alt((multispace1, plain_comment, block_comment))))(input)
+}
+
+/// The `##` lines before a statement without their `##`, one line each,
+/// skipping the whitespace and other comments around them
+fn doc_comments(input: &str) -> ParseResult<'_, Option<String>> {
+    let (mut input, _) = whitespace_before_doc(input)?;
+    let mut lines: Vec<&str> = vec![];
+    while let Ok((rest, line)) = preceded(tag("##"), not_line_ending::<&str, SyntaxError>)(input) {
+        lines.push(line.strip_prefix(' ').unwrap_or(line).trim_end());
+        (input, _) = whitespace_before_doc(rest)?;
+    }
+
+    Ok((input, (!lines.is_empty()).then(|| lines.join("\n"))))
+}
+
+/// A parse event for `rule`, with the rest of the line it is tried on
+fn trace_rule(rule: &str, input: &str) {
+    trace::event(
+        Category::Parse,
+        rule,
+        &[("input", &input.lines().next().unwrap_or(""))],
+    );
+}
+
+/// An integer exponent, which may be negative: m^-2
+fn parse_exponent(input: &str) -> ParseResult<'_, i32> {
+    let (input, power) = context(
+        "an integer exponent",
+        map_res(recognize(pair(opt(char('-')), digit1)), |power: &str| {
+            power.parse::<i32>()
+        }),
+    )(input)?;
+
+    Ok((input, power))
+}
+
+/// Which names a unit expression may use. Variable annotations may name
+/// dimensions as well as units, [currency/time]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnitNames {
+    Units,
+    UnitsAndDimensions,
+}
+
+impl UnitNames {
+    fn resolve(self, name: &str, power: i32) -> Option<Unit> {
+        let dimension = match self {
+            UnitNames::Units => None,
+            UnitNames::UnitsAndDimensions => units::resolve_dimension(name, power),
+        };
+        dimension
+            .map(|quantity| Unit {
+                unit: UnitIdentity::Base(1.0),
+                quantity,
+            })
+            .or_else(|| units::resolve(name, power))
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            UnitNames::Units => "unit",
+            UnitNames::UnitsAndDimensions => "unit or dimension",
+        }
+    }
+}
+
+/// A unit alias or a parenthesised unit expression, with an optional power:
+/// km, m^2, s^-1, (m/s)^2. The power defaults to 1
+fn parse_unit_factor(input: &str, names: UnitNames) -> ParseResult<'_, Unit> {
+    trace_rule("parse_unit_factor", input);
+    let start = input;
+
+    if let Ok((input, _)) = token("(")(input) {
+        let (input, unit) = delimited(
+            whitespace0,
+            |input| parse_unit_expression(input, names),
+            whitespace0,
+        )(input)?;
+        let (input, _) = lexeme(token(")"))(input)?;
+        let (input, power) = opt(preceded(lexeme(char('^')), cut(lexeme(parse_exponent))))(input)?;
+        let unit = unit
+            .powi(power.unwrap_or(1))
+            .map_err(|error| SyntaxError::invalid(start, error.to_string()))?;
+        return Ok((input, unit));
+    }
+
+    let alias_start = input;
+    let (input, unit_alias) = context(
+        "a unit",
+        take_while1(|c: char| c.is_alphabetic() || c == '$' || c == '£'),
+    )(input)?;
+    let (input, power) = opt(preceded(lexeme(char('^')), cut(lexeme(parse_exponent))))(input)?;
+
+    match names.resolve(unit_alias, power.unwrap_or(1)) {
+        Some(unit) => {
+            trace::event(
+                Category::Units,
+                "unit",
+                &[
+                    ("alias", &unit_alias),
+                    ("power", &power.unwrap_or(1)),
+                    ("unit", &unit),
+                ],
+            );
+            Ok((input, unit))
+        }
+        // the unit exists, but raising it to the power overflows its exponents
+        None if names.resolve(unit_alias, 1).is_some() => Err(SyntaxError::invalid(
+            alias_start,
+            EvalError::ExponentOverflow {
+                span: Span::default(),
+            }
+            .to_string(),
+        )),
+        None => Err(SyntaxError::invalid(
+            alias_start,
+            format!("unknown {} `{}`", names.description(), unit_alias),
+        )),
+    }
+}
+
+/// Unit factors combined left to right with *, · or /: kg*m/s^2, USD/month
+fn parse_unit_expression(input: &str, names: UnitNames) -> ParseResult<'_, Unit> {
+    trace_rule("parse_unit_expression", input);
+    let start = input;
+    let (input, first) = parse_unit_factor(input, names)?;
+    let (input, rest) = many0(pair(
+        delimited(
+            whitespace0,
+            alt((token("*"), token("·"), token("/"))),
+            whitespace0,
+        ),
+        |input| parse_unit_factor(input, names),
     ))(input)?;
-    println!("  parsed unit {}", unit_alias.clone());
-    let (input, _) = tag("^")(input)?;
-
-    // TODO: add some sugar here so we can write 1[m] instead of 1[m^1]
-    // println!("  parsing unit {}", input.clone());
-    let (input, power_string) = digit1(input)?;
-    println!("  parsed power {}", power_string.clone());
-
-    let power = power_string.parse::<i64>().unwrap();
-    // println!("  parsing unit {}", input.clone());
-    let (input, _) = tag("]")(input)?;
-    // println!("  parsing unit {}", input.clone());
-
-    // TODO: We can also have a parser for each unit
-    let dimension = match unit_alias {
-        "none" => Unit {
-            unit: UnitIdentity::None(1.0),
-            quantity: Quantity::None(1),
-        },
-        // Time has a constant power of 1
-        "days" | "day" | "d" => Unit {
-            unit: UnitIdentity::Day(86400.0), // conversion_factor to base unit, Second in this case
-            quantity: Quantity::Time(1),      // Power of the quantity, so this is Days^1
-        },
-        "hours" | "hour" | "hr" => Unit {
-            unit: UnitIdentity::Hour(3600.0),
-            quantity: Quantity::Time(1),
-        },
-        "minutes" | "minute" | "min" => Unit {
-            unit: UnitIdentity::Minute(60.0),
-            quantity: Quantity::Time(1),
-        },
-        "seconds" | "second" | "s" => Unit {
-            unit: UnitIdentity::Second(1.0),
-            quantity: Quantity::Time(1),
-        },
-        "meters" | "meter" | "m" => match power {
-            1 => Unit {
-                unit: UnitIdentity::Meter(1.0),
-                quantity: Quantity::Length(power),
-            },
-            2 => Unit {
-                unit: UnitIdentity::SquareMeter(1.0),
-                quantity: Quantity::Length(power),
-            },
-            3 => Unit {
-                unit: UnitIdentity::Kilometer(1.0),
-                quantity: Quantity::Length(power),
-            },
-            _ => todo!("other dimensions in meters"),
-        },
-        "kilometers" | "kilometer" | "km" => match power {
-            1 => Unit {
-                unit: UnitIdentity::Kilometer(1000.0),
-                quantity: Quantity::Length(power),
-            },
-            2 => Unit {
-                unit: UnitIdentity::SquareKilometer(1000000.0),
-                quantity: Quantity::Length(power),
-            },
-            3 => Unit {
-                unit: UnitIdentity::CubicKilometer(1000000000.0),
-                quantity: Quantity::Length(power),
-            },
-            _ => todo!("other dimensions in meters"),
-        },
-        // Currency has a constant power of 1
-        "USD" | "$" => Unit {
-            unit: UnitIdentity::USD(1.0), // USD is the base unit
-            quantity: Quantity::Currency(1),
-        },
-        "GBP" | "£" => Unit {
-            unit: UnitIdentity::Day(1.2),
-            quantity: Quantity::Currency(1),
-        },
-        _ => panic!("Unsupported unit alias {}", unit_alias),
-    };
 
-    Ok((input, dimension))
+    let compound = !rest.is_empty();
+    let unit = rest
+        .into_iter()
+        .try_fold(first, |unit, (operator, factor)| match operator {
+            "/" => unit.product(factor.powi(-1)?),
+            _ => unit.product(factor),
+        })
+        .map_err(|error| SyntaxError::invalid(start, error.to_string()))?;
+    if compound {
+        trace::event(Category::Units, "unit_expression", &[("unit", &unit)]);
+    }
+
+    Ok((input, unit))
+}
+
+/// A bracketed unit expression: [km^2], [USD/month], [kg*m/s^2]
+fn parse_length(input: &str) -> ParseResult<'_, Unit> {
+    trace_rule("parse_length", input);
+
+    let (input, _) = token("[")(input)?;
+    let (input, unit) = delimited(
+        whitespace0,
+        |input| parse_unit_expression(input, UnitNames::Units),
+        whitespace0,
+    )(input)?;
+    let (input, _) = token("]")(input)?;
+
+    // a single unit keeps its own identity, so [km] stays Meter(1000.0)
+    Ok((input, unit))
+}
+
+/// What a variable is declared to hold, written after its name:
+/// wage[USD/month] or wage[currency/time]. Only the quantity is checked
+fn parse_annotation(input: &str) -> ParseResult<'_, Annotation> {
+    trace_rule("parse_annotation", input);
+
+    let (rest, _) = token("[")(input)?;
+    let (rest, unit) = delimited(
+        whitespace0,
+        |input| parse_unit_expression(input, UnitNames::UnitsAndDimensions),
+        whitespace0,
+    )(rest)?;
+    let (rest, _) = token("]")(rest)?;
+
+    // parsed again to tell [USD/month] from [currency/time]
+    let written = &input[..input.len() - rest.len()];
+    let annotation = match parse_length(written) {
+        Ok(("", unit)) => Annotation::Unit(unit),
+        _ => Annotation::Dimension(unit.quantity),
+    };
+    Ok((rest, annotation))
 }
 
 /// Switch on dimensions
-fn parse_dimension(input: &str) -> IResult<&str, Unit> {
-    println!("reached parse_dimension {}", input.clone());
+fn parse_dimension(input: &str) -> ParseResult<'_, Unit> {
+    trace_rule("parse_dimension", input);
     let (input, dimension) = parse_length(input)?;
-    // let (input, dimension) = delimited(tag("["), alt((parse_length, parse_volume)), tag("]"))(input)?;
 
     Ok((input, dimension))
 }
 
-fn parse_number(number: &str) -> IResult<&str, AstNode> {
-    println!("reached parse_number {}", number.clone());
-    let (input, number) = double(number)?;
+/// The unit after a number or vector, and what to divide the number by.
+/// Without a unit the value is dimensionless, and `%` makes it a
+/// dimensionless fraction, 15% is 0.15
+fn parse_unit_suffix(input: &str) -> ParseResult<'_, (f64, Unit)> {
+    if let Ok((input, _)) = lexeme(token("%"))(input) {
+        return Ok((input, (100.0, Unit::dimensionless())));
+    }
+    let (input, unit) = opt(lexeme(parse_dimension))(input)?;
+
+    Ok((input, (1.0, unit.unwrap_or_else(Unit::dimensionless))))
+}
+
+/// A decimal number, 2, -1.5, .5 or 11e-1. Unlike nom's `double` this does
+/// not read `inf` or `nan`, which would take the start of names like `inflation`
+fn float(input: &str) -> ParseResult<'_, f64> {
+    context(
+        "a number",
+        map_res(recognize_float, |number: &str| number.parse::<f64>()),
+    )(input)
+}
+
+fn parse_number(number: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_number", number);
+    let (input, number) = float(number)?;
 
-    let (input, unit) = parse_dimension(input)?;
+    let (input, (divisor, unit)) = parse_unit_suffix(input)?;
 
     Ok((
         input,
         AstNode::Double {
-            value: Value::Float(number),
-            unit: unit,
+            value: Value::Float(number / divisor),
+            unit,
         },
     ))
 }
 
-fn parse_vector(input: &str) -> IResult<&str, AstNode> {
-    println!("reached parse_vector {}", input.clone());
+fn parse_vector(input: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_vector", input);
 
-    println!("  reached vector bracket open {}", input.clone());
-    let (input, _) = tag("[")(input)?;
-    println!("  reached vector {}", input.clone());
-    let (input, vector) = many1(delimited(space0, double, space0))(input)?;
-    println!("  parsed vector {:#?}", vector.clone());
-    println!("  reached vector bracket close {}", input.clone());
-    let (input, _) = tag("]")(input)?;
+    let (input, _) = token("[")(input)?;
+    let (input, vector) = many1(delimited(whitespace0, float, whitespace0))(input)?;
+    let (input, _) = token("]")(input)?;
 
-    let (input, unit) = parse_dimension(input)?;
+    let (input, (divisor, unit)) = parse_unit_suffix(input)?;
 
     Ok((
         input,
         AstNode::Vector {
-            value: Value::Vec(vector),
-            unit: unit,
+            value: Value::Vec(vector.iter().map(|value| value / divisor).collect()),
+            unit,
         },
     ))
 }
 
-fn parse_value(input: &str) -> IResult<&str, AstNode> {
-    println!("reached parse_value {}", input.clone());
+fn parse_value(input: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_value", input);
     alt((parse_vector, parse_number))(input)
 }
 
-fn parse_name(name: &str) -> IResult<&str, AstNode> {
-    println!("reached parse_name {}", name.clone());
-    let (input, name) = alpha1(name)?;
+/// A column of the csv files given on the command line, csv{height[km]}.
+/// Without a unit its values are dimensionless
+fn parse_csv(input: &str) -> ParseResult<'_, AstNode> {
+    let span = Span {
+        from_end: input.len(),
+    };
+    // not naming `csv` as expected, a name is expected at the same place
+    let (input, _) = match terminated(keyword("csv"), lexeme(token("{")))(input) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+                input,
+                ErrorKind::Tag,
+            )))
+        }
+    };
+    let (input, column) = cut(preceded(
+        whitespace0,
+        context(
+            "a column name",
+            take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+        ),
+    ))(input)?;
+    let (input, unit) = cut(opt(lexeme(parse_length)))(input)?;
+    let (input, _) = cut(lexeme(token("}")))(input)?;
+
+    Ok((
+        input,
+        AstNode::Csv {
+            column: column.to_string(),
+            unit: unit.unwrap_or_else(Unit::dimensionless),
+            span,
+        },
+    ))
+}
+
+/// Words that cannot be part of a name, because they are syntax or are kept
+/// for syntax to come
+pub const RESERVED: [&str; 11] = [
+    "as", "in", "csv", "print", "let", "fn", "if", "else", "import", "true", "false",
+];
 
-    Ok((input, AstNode::Name(name.to_string())))
+/// One part of a name, a letter or `_` and then any letters, digits or `_`
+fn identifier(input: &str) -> ParseResult<'_, &str> {
+    recognize(pair(
+        satisfy(|c: char| c.is_ascii_alphabetic() || c == '_'),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(input)
 }
 
-fn parse_operator(input: &str) -> IResult<&str, &str> {
-    println!("reached parse_operator {}", input.clone());
-    Ok(alt((
-        terminated(preceded(space0, tag("+")), space0),
-        terminated(preceded(space0, tag("-")), space0),
-        terminated(preceded(space0, tag("*")), space0),
-        terminated(preceded(space0, tag("/")), space0),
-        terminated(preceded(space0, tag("^")), space0),
-    ))(input)?)
+/// A name, which may be namespaced with dots: q1_revenue, costs.labour
+fn parse_name(name: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_name", name);
+    let span = Span {
+        from_end: name.len(),
+    };
+    let (input, text) = context(
+        "a name",
+        recognize(pair(identifier, many0(pair(char('.'), identifier)))),
+    )(name)?;
+
+    if let Some(word) = text.split('.').find(|part| RESERVED.contains(part)) {
+        return Err(SyntaxError::invalid(
+            name,
+            format!(
+                "`{}` is a reserved word, so it cannot be used in a name",
+                word
+            ),
+        ));
+    }
+    Ok((input, AstNode::Name(text.to_string(), span)))
 }
 
-fn parse_expression(input: &str) -> IResult<&str, AstNode> {
-    println!("reached parse_expression {}", input.clone());
+fn parse_operator(input: &str) -> ParseResult<'_, BinaryOperation> {
+    trace_rule("parse_operator", input);
+    let (input, operator) = terminated(
+        lexeme(context(
+            "an operator",
+            alt((token("+"), token("-"), token("*"), token("/"), token("^"))),
+        )),
+        whitespace0,
+    )(input)?;
+    let operation = match operator {
+        "+" => BinaryOperation::Add,
+        "-" => BinaryOperation::Subtract,
+        "*" => BinaryOperation::Multiply,
+        "/" => BinaryOperation::Divide,
+        _ => BinaryOperation::Power,
+    };
 
-    let (input, _) = tag("(")(input)?;
-    let (input, lhs) = alt((parse_value, parse_name, parse_expression))(input)?;
-    let (input, operator) = parse_operator(input)?;
-    let (input, rhs) = alt((parse_expression, parse_name, parse_value))(input)?;
-    let (input, _) = tag(")")(input)?;
-    Ok((
-        input,
+    Ok((input, operation))
+}
+
+/// A `terminator` after an expression. When it is missing an operator
+/// would also have done, so the error says so. The error is at the end of
+/// the expression when the next token is on a later line, so a forgotten `;`
+/// is reported on the line it was forgotten on
+fn end_of_expression<'a>(
+    terminator: &'static str,
+) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    move |input| match lexeme(token(terminator))(input) {
+        Err(nom::Err::Error(error)) => {
+            let mut error = match parse_operator(input) {
+                Err(nom::Err::Error(operator_error)) => {
+                    nom::error::ParseError::or(error, operator_error)
+                }
+                _ => error,
+            };
+            if input[..input.len() - error.input.len()].contains('\n') {
+                error.input = input;
+            }
+            Err(nom::Err::Error(error))
+        }
+        result => result,
+    }
+}
+
+/// A parenthesised expression. Its span covers the parentheses, so errors
+/// point at the `(`
+fn parse_parenthesised(input: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_parenthesised", input);
+    let paren = Span {
+        from_end: input.len(),
+    };
+    let (input, _) = token("(")(input)?;
+    let (input, expression) = lexeme(parse_expression)(input)?;
+    let (input, _) = end_of_expression(")")(input)?;
+
+    let expression = match expression {
         AstNode::Expression {
+            operation,
+            lhs,
+            rhs,
+            ..
+        } => AstNode::Expression {
+            operation,
+            lhs,
+            rhs,
+            span: paren,
+        },
+        expression => expression,
+    };
+    Ok((input, expression))
+}
+
+/// A value, a name, a parenthesised expression, or any of those negated.
+/// `-x` is `-1 * x`, so it needs no node of its own. Powers bind tighter
+/// than negation, -a^2 is -(a^2)
+fn parse_operand(input: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_operand", input);
+    let span = Span {
+        from_end: input.len(),
+    };
+    if let Ok((rest, _)) = terminated(token("-"), whitespace0)(input) {
+        let (rest, operand) = parse_binary(rest, BinaryOperation::Power.precedence())?;
+        return Ok((
+            rest,
+            match operand {
+                // a literal keeps its sign, -2[m] is a number
+                AstNode::Double { value, unit } => AstNode::Double {
+                    value: value.scale(-1.0),
+                    unit,
+                },
+                AstNode::Vector { value, unit } => AstNode::Vector {
+                    value: value.scale(-1.0),
+                    unit,
+                },
+                operand => AstNode::Expression {
+                    operation: BinaryOperation::Multiply,
+                    lhs: Box::new(AstNode::Double {
+                        value: Value::Float(-1.0),
+                        unit: Unit::dimensionless(),
+                    }),
+                    rhs: Box::new(operand),
+                    span,
+                },
+            },
+        ));
+    }
+
+    alt((parse_value, parse_csv, parse_name, parse_parenthesised))(input)
+}
+
+/// Operators and the operands between them, grouped by precedence climbing.
+/// Operators of equal precedence group to the left, a - b - c is (a - b) - c,
+/// except `^` which groups to the right, a^b^c is a^(b^c)
+fn parse_binary(input: &str, min_precedence: u8) -> ParseResult<'_, AstNode> {
+    let span = Span {
+        from_end: input.len(),
+    };
+    let (mut input, mut lhs) = parse_operand(input)?;
+
+    loop {
+        let (rest, operation) = match parse_operator(input) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Error(_)) => break,
+            Err(error) => return Err(error),
+        };
+        if operation.precedence() < min_precedence {
+            break;
+        }
+
+        let rhs_precedence = match operation {
+            BinaryOperation::Power => operation.precedence(),
+            _ => operation.precedence() + 1,
+        };
+        let (rest, rhs) = parse_binary(rest, rhs_precedence)?;
+        lhs = AstNode::Expression {
+            operation,
             lhs: Box::new(lhs),
             rhs: Box::new(rhs),
-            operation: match operator {
-                "+" => BinaryOperation::Add,
-                "-" => BinaryOperation::Subtract,
-                "*" => BinaryOperation::Multiply,
-                "/" => BinaryOperation::Divide,
-                _ => panic!("Unsupported binary operation {}", operator),
+            span,
+        };
+        input = rest;
+    }
+
+    Ok((input, lhs))
+}
+
+/// A word that is not the start of a longer word, `as` but not `ash`
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    move |input| {
+        terminated(
+            token(word),
+            not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+        )(input)
+        .map_err(|error| error.map(|_| SyntaxError::expected(input, format!("`{}`", word))))
+    }
+}
+
+/// `as [unit]` or `in [unit]` after an expression, converting its result
+fn parse_conversion(input: &str) -> ParseResult<'_, (Unit, Span)> {
+    let (input, _) = whitespace0(input)?;
+    let span = Span {
+        from_end: input.len(),
+    };
+    let (input, _) = alt((keyword("as"), keyword("in")))(input)?;
+    let (input, unit) = cut(lexeme(parse_length))(input)?;
+
+    Ok((input, (unit, span)))
+}
+
+/// An arithmetic expression with the usual precedence, * and / before + and -,
+/// optionally converted to another unit, which applies to all of it
+fn parse_expression(input: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_expression", input);
+    let (input, expression) = parse_binary(input, 0)?;
+    let (input, conversion) = opt(parse_conversion)(input)?;
+
+    Ok((
+        input,
+        match conversion {
+            Some((unit, span)) => AstNode::Conversion {
+                expr: Box::new(expression),
+                unit,
+                span,
             },
+            None => expression,
         },
     ))
 }
 
-fn parse_variable(input: &str) -> IResult<&str, AstNode> {
-    println!("reached parse_variable {}", input.clone());
+fn parse_variable(input: &str) -> ParseResult<'_, AstNode> {
+    trace_rule("parse_variable", input);
+    let (input, doc) = doc_comments(input)?;
+    let name_start = input;
     let (input, name) = parse_name(input)?;
-    let (input, _) = tag(" = ")(input)?;
-    let (input, expr) = terminated(alt((parse_value, parse_expression)), char(';'))(input)?;
+    // a variable named like a unit would make 2[km] and 2 * km mean different
+    // things. Only exact aliases count, so mm and cs are still names
+    if let AstNode::Name(name, _) = &name {
+        if let Some(unit) = units::lookup(name) {
+            return Err(SyntaxError::invalid(
+                name_start,
+                format!(
+                    "`{}` is the unit `{}`, so it cannot be a variable name",
+                    name, unit.symbol
+                ),
+            ));
+        }
+    }
+    let (input, annotation) = opt(lexeme(parse_annotation))(input)?;
+    let (input, _) = lexeme(token("="))(input)?;
+    let (input, expr) = terminated(lexeme(parse_expression), end_of_expression(";"))(input)?;
 
     Ok((
         input,
         AstNode::Variable {
             name: Box::new(name),
+            annotation,
             expr: Box::new(expr),
+            doc,
         },
     ))
 }
 
-pub fn parse_line(input: &str) -> IResult<&str, Vec<AstNode>> {
-    println!("reached parse_line {}", input.clone());
-    many0(preceded(space0, parse_variable))(input)
+/// Every statement in the input. Statements end at `;` and may run over
+/// several lines, newlines are whitespace like any other
+pub fn parse_statements(input: &str) -> ParseResult<'_, Vec<AstNode>> {
+    trace_rule("parse_statements", input);
+    let mut statements = vec![];
+    let mut input = input;
+    // doc comments with no statement after them document nothing
+    while !doc_comments(input)?.0.is_empty() {
+        let (rest, statement) = parse_variable(input)?;
+        statements.push(statement);
+        input = rest;
+    }
+
+    Ok(("", statements))
+}
+
+/// A parse failure somewhere in `source`, with the line and column of it
+fn to_parse_error(file_name: &str, source: &str, error: nom::Err<SyntaxError>) -> ParseError {
+    let error = match error {
+        nom::Err::Error(error) | nom::Err::Failure(error) => error,
+        nom::Err::Incomplete(_) => SyntaxError {
+            input: "",
+            expected: vec![],
+            message: Some("unexpected end of input".to_string()),
+        },
+    };
+
+    let (line, column, source_line) = position(
+        Span {
+            from_end: error.input.len(),
+        },
+        source,
+    );
+    ParseError::new(
+        file_name,
+        source_line,
+        line,
+        column,
+        error.expected,
+        error.message,
+    )
+}
+
+/// The line a statement starts on, spans measure from the end of the source
+fn start_line(statement: &AstNode, source: &str) -> usize {
+    match statement {
+        AstNode::Variable { name, .. } => match name.as_ref() {
+            AstNode::Name(_, span) => position(*span, source).0,
+            _ => 1,
+        },
+        _ => 1,
+    }
+}
+
+/// Parse a whole source file. Statements are grouped by the line they start
+/// on, and `file_name` is only used to report where an error is
+pub fn parse_program(file_name: &str, source: &str) -> Result<Vec<Vec<AstNode>>, ParseError> {
+    let (_, statements) =
+        parse_statements(source).map_err(|error| to_parse_error(file_name, source, error))?;
+
+    let mut program: Vec<(usize, Vec<AstNode>)> = vec![];
+    for statement in statements {
+        let line = start_line(&statement, source);
+        match program.last_mut() {
+            Some((last_line, statements)) if *last_line == line => statements.push(statement),
+            _ => program.push((line, vec![statement])),
+        }
+    }
+
+    Ok(program
+        .into_iter()
+        .map(|(_, statements)| statements)
+        .collect())
+}
+
+/// Parse a single expression on its own, like `2[km] / 30[min] as [km/h]`,
+/// with nothing but whitespace around it
+pub fn parse_single_expression(file_name: &str, source: &str) -> Result<AstNode, ParseError> {
+    let result = delimited(whitespace0, parse_expression, whitespace0)(source).and_then(
+        |(rest, expression)| match rest.is_empty() {
+            true => Ok(expression),
+            false => Err(nom::Err::Error(SyntaxError::expected(
+                rest,
+                "an operator".to_string(),
+            ))),
+        },
+    );
+
+    result.map_err(|error| to_parse_error(file_name, source, error))
+}
+
+/// The parse result with its spans reset, so it compares equal to a tree
+/// built without positions
+#[cfg(test)]
+fn spanless(result: ParseResult<'_, AstNode>) -> ParseResult<'_, AstNode> {
+    result.map(|(input, node)| (input, node.without_spans()))
 }
 
 #[test]
 fn test_parse_number() {
     assert_eq!(
-        parse_number("11e-1[m]"),
+        parse_number("11e-1[m^1]"),
         Ok((
             "",
             AstNode::Double {
                 value: Value::Float(1.1),
-                dimension: Unit {
-                    unit: Unit {
-                        unit: UnitIdentity::Meter,
-                        conversion_factor: 1.0
-                    },
-                    power: 1
+                unit: Unit {
+                    unit: UnitIdentity::Meter(1.0),
+                    quantity: Quantity::base(BaseDimension::Length, 1)
                 }
             }
         ))
     );
     assert_eq!(
-        parse_number("1[meter]"),
+        parse_number("1[meter^1]"),
         Ok((
             "",
             AstNode::Double {
                 value: Value::Float(1.0),
-                dimension: Unit {
-                    unit: Unit {
-                        unit: UnitIdentity::Meter,
-                        conversion_factor: 1.0
-                    },
-                    power: 1
+                unit: Unit {
+                    unit: UnitIdentity::Meter(1.0),
+                    quantity: Quantity::base(BaseDimension::Length, 1)
                 },
             }
         ))
     );
     assert_eq!(
-        parse_number("1.1[km]"),
+        parse_number("1.1[km^2]"),
         Ok((
             "",
             AstNode::Double {
                 value: Value::Float(1.1),
-                dimension: Unit {
-                    unit: UnitIdentity::Meter,
-                    conversion_factor: 1.0
-                },
-                power: 1
+                unit: Unit {
+                    unit: UnitIdentity::Meter(1000000.0),
+                    quantity: Quantity::base(BaseDimension::Length, 2)
+                }
             }
         ))
     );
     assert_eq!(
-        parse_number("9999999.987654[m]"),
+        parse_number("9999999.987654[m^4]"),
         Ok((
             "",
             AstNode::Double {
                 value: Value::Float(9999999.987654),
-                dimension: Unit {
-                    unit: UnitIdentity::Meter,
-                    conversion_factor: 1.0
-                },
-                power: 1
+                unit: Unit {
+                    unit: UnitIdentity::Meter(1.0),
+                    quantity: Quantity::base(BaseDimension::Length, 4)
+                }
             }
         ))
     );
 }
+#[test]
+fn test_parse_names_like_numbers() {
+    // `inf` and `nan` are not numbers, so names may start with them
+    let program = parse_program("test.r2", "inflation = 2%;\nx = inflation * 2;\nnan = 1;");
+    assert_eq!(program.map(|program| program.len()), Ok(3));
+    assert!(parse_number("inf").is_err());
+    assert!(parse_vector("[1 nan]").is_err());
+}
+
 #[test]
 fn test_parse_name() {
     assert_eq!(
-        parse_name("test"),
-        Ok(("", AstNode::Name("test".to_string())))
+        spanless(parse_name("test")),
+        Ok(("", AstNode::Name("test".to_string(), Span::default())))
     );
     assert_eq!(
-        parse_name("test"),
-        Ok(("", AstNode::Name("test".to_string())))
+        spanless(parse_name("test")),
+        Ok(("", AstNode::Name("test".to_string(), Span::default())))
     );
 }
 
 #[test]
 fn test_parse_variable() {
     assert_eq!(
-        parse_variable("test = 1.2[m];"),
+        spanless(parse_variable("test = 1.2[m^1];")),
         Ok((
             "",
             AstNode::Variable {
-                name: Box::new(AstNode::Name("test".to_string())),
+                name: Box::new(AstNode::Name("test".to_string(), Span::default())),
+                annotation: None,
+                doc: None,
                 expr: Box::new(AstNode::Double {
                     value: Value::Float(1.2),
-                    dimension: Unit { unit: Unit::Meter }
+                    unit: Unit {
+                        unit: UnitIdentity::Meter(1.0),
+                        quantity: Quantity::base(BaseDimension::Length, 1)
+                    }
                 })
             }
         ))
     );
 
     assert_eq!(
-        parse_variable("var = -2[kilometers];"),
+        spanless(parse_variable("var = -2[kilometers^1];")),
         Ok((
             "",
             AstNode::Variable {
-                name: Box::new(AstNode::Name("var".to_string())),
+                name: Box::new(AstNode::Name("var".to_string(), Span::default())),
+                annotation: None,
+                doc: None,
                 expr: Box::new(AstNode::Double {
                     value: Value::Float(-2.0),
-                    dimension: Unit {
-                        unit: Unit::Kilometer
+                    unit: Unit {
+                        unit: UnitIdentity::Meter(1000.0),
+                        quantity: Quantity::base(BaseDimension::Length, 1)
                     }
                 })
             }
@@ -345,27 +907,34 @@ fn test_parse_variable() {
 #[test]
 fn test_parse_expression() {
     assert_eq!(
-        parse_expression("(2[km] / 2[m])"),
+        spanless(parse_expression("(2[km^1] / 2[m^1])")),
         Ok((
             "",
             AstNode::Expression {
                 operation: BinaryOperation::Divide,
                 lhs: Box::new(AstNode::Double {
                     value: Value::Float(2.0),
-                    dimension: Unit {
-                        unit: Unit::Kilometer
+                    unit: Unit {
+                        unit: UnitIdentity::Meter(1000.0),
+                        quantity: Quantity::base(BaseDimension::Length, 1)
                     }
                 }),
                 rhs: Box::new(AstNode::Double {
                     value: Value::Float(2.0),
-                    dimension: Unit { unit: Unit::Meter }
-                })
+                    unit: Unit {
+                        unit: UnitIdentity::Meter(1.0),
+                        quantity: Quantity::base(BaseDimension::Length, 1)
+                    }
+                }),
+                span: Span::default(),
             }
         ))
     );
 
     assert_eq!(
-        parse_expression("((2[m] / 2[km]) + (4[km] * 4[m]))"),
+        spanless(parse_expression(
+            "((2[m^1] / 2[km^1]) + (4[km^1] * 4[m^1]))"
+        )),
         Ok((
             "",
             AstNode::Expression {
@@ -374,28 +943,39 @@ fn test_parse_expression() {
                     operation: BinaryOperation::Divide,
                     lhs: Box::new(AstNode::Double {
                         value: Value::Float(2.0),
-                        dimension: Unit { unit: Unit::Meter }
+                        unit: Unit {
+                            unit: UnitIdentity::Meter(1.0),
+                            quantity: Quantity::base(BaseDimension::Length, 1)
+                        }
                     }),
                     rhs: Box::new(AstNode::Double {
                         value: Value::Float(2.0),
-                        dimension: Unit {
-                            unit: Unit::Kilometer
+                        unit: Unit {
+                            unit: UnitIdentity::Meter(1000.0),
+                            quantity: Quantity::base(BaseDimension::Length, 1)
                         }
-                    })
+                    }),
+                    span: Span::default(),
                 }),
                 rhs: Box::new(AstNode::Expression {
                     operation: BinaryOperation::Multiply,
                     lhs: Box::new(AstNode::Double {
                         value: Value::Float(4.0),
-                        dimension: Unit {
-                            unit: Unit::Kilometer
+                        unit: Unit {
+                            unit: UnitIdentity::Meter(1000.0),
+                            quantity: Quantity::base(BaseDimension::Length, 1)
                         }
                     }),
                     rhs: Box::new(AstNode::Double {
                         value: Value::Float(4.0),
-                        dimension: Unit { unit: Unit::Meter }
-                    })
-                })
+                        unit: Unit {
+                            unit: UnitIdentity::Meter(1.0),
+                            quantity: Quantity::base(BaseDimension::Length, 1)
+                        }
+                    }),
+                    span: Span::default(),
+                }),
+                span: Span::default(),
             }
         ))
     );
@@ -404,62 +984,84 @@ fn test_parse_expression() {
 #[test]
 fn parse_variable_expression() {
     assert_eq!(
-        parse_variable("var = (2[m] / 2[km]);"),
+        spanless(parse_variable("var = (2[m^1] / 2[km^1]);")),
         Ok((
             "",
             AstNode::Variable {
-                name: Box::new(AstNode::Name("var".to_string())),
+                name: Box::new(AstNode::Name("var".to_string(), Span::default())),
+                annotation: None,
+                doc: None,
                 expr: Box::new(AstNode::Expression {
                     operation: BinaryOperation::Divide,
                     lhs: Box::new(AstNode::Double {
                         value: Value::Float(2.0),
-                        dimension: Unit { unit: Unit::Meter }
+                        unit: Unit {
+                            unit: UnitIdentity::Meter(1.0),
+                            quantity: Quantity::base(BaseDimension::Length, 1)
+                        }
                     }),
                     rhs: Box::new(AstNode::Double {
                         value: Value::Float(2.0),
-                        dimension: Unit {
-                            unit: Unit::Kilometer
+                        unit: Unit {
+                            unit: UnitIdentity::Meter(1000.0),
+                            quantity: Quantity::base(BaseDimension::Length, 1)
                         }
-                    })
+                    }),
+                    span: Span::default(),
                 })
             }
         ))
     );
 
     assert_eq!(
-        parse_variable("var = ((2[m] * 3[kilometers]) * (4[meters] + 5[km]));"),
+        spanless(parse_variable(
+            "var = ((2[m^1] * 3[kilometers^1]) * (4[meters^1] + 5[km^1]));"
+        )),
         Ok((
             "",
             AstNode::Variable {
-                name: Box::new(AstNode::Name("var".to_string())),
+                name: Box::new(AstNode::Name("var".to_string(), Span::default())),
+                annotation: None,
+                doc: None,
                 expr: Box::new(AstNode::Expression {
                     operation: BinaryOperation::Multiply,
                     lhs: Box::new(AstNode::Expression {
                         operation: BinaryOperation::Multiply,
                         lhs: Box::new(AstNode::Double {
                             value: Value::Float(2.0),
-                            dimension: Unit { unit: Unit::Meter }
+                            unit: Unit {
+                                unit: UnitIdentity::Meter(1.0),
+                                quantity: Quantity::base(BaseDimension::Length, 1)
+                            }
                         }),
                         rhs: Box::new(AstNode::Double {
                             value: Value::Float(3.0),
-                            dimension: Unit {
-                                unit: Unit::Kilometer
+                            unit: Unit {
+                                unit: UnitIdentity::Meter(1000.0),
+                                quantity: Quantity::base(BaseDimension::Length, 1)
                             }
                         }),
+                        span: Span::default(),
                     }),
                     rhs: Box::new(AstNode::Expression {
                         operation: BinaryOperation::Add,
                         lhs: Box::new(AstNode::Double {
                             value: Value::Float(4.0),
-                            dimension: Unit { unit: Unit::Meter }
+                            unit: Unit {
+                                unit: UnitIdentity::Meter(1.0),
+                                quantity: Quantity::base(BaseDimension::Length, 1)
+                            }
                         }),
                         rhs: Box::new(AstNode::Double {
                             value: Value::Float(5.0),
-                            dimension: Unit {
-                                unit: Unit::Kilometer
+                            unit: Unit {
+                                unit: UnitIdentity::Meter(1000.0),
+                                quantity: Quantity::base(BaseDimension::Length, 1)
                             }
                         }),
-                    })
+                        span: Span::default(),
+                    }),
+                    span: Span::default(),
                 })
             }
         ))
@@ -469,44 +1071,804 @@ fn parse_variable_expression() {
 #[test]
 fn parse_variables_and_abstract_expressions() {
     assert_eq!(
-        parse_line("x = (2[m] * 2[kilometer]); y = 1[km]; z = (x + y);"),
+        parse_statements("x = (2[m^1] * 2[kilometer^1]); y = 1[km^1]; z = (x + y);").map(
+            |(input, statements)| (
+                input,
+                statements.into_iter().map(AstNode::without_spans).collect()
+            )
+        ),
         Ok((
             "",
             vec![
                 AstNode::Variable {
-                    name: Box::new(AstNode::Name("x".to_string())),
+                    name: Box::new(AstNode::Name("x".to_string(), Span::default())),
+                    annotation: None,
+                    doc: None,
                     expr: Box::new(AstNode::Expression {
                         operation: BinaryOperation::Multiply,
                         lhs: Box::new(AstNode::Double {
                             value: Value::Float(2.0),
-                            dimension: Unit { unit: Unit::Meter }
+                            unit: Unit {
+                                unit: UnitIdentity::Meter(1.0),
+                                quantity: Quantity::base(BaseDimension::Length, 1)
+                            }
                         }),
                         rhs: Box::new(AstNode::Double {
                             value: Value::Float(2.0),
-                            dimension: Unit {
-                                unit: Unit::Kilometer
+                            unit: Unit {
+                                unit: UnitIdentity::Meter(1000.0),
+                                quantity: Quantity::base(BaseDimension::Length, 1)
                             }
-                        })
+                        }),
+                        span: Span::default(),
                     })
                 },
                 AstNode::Variable {
-                    name: Box::new(AstNode::Name("y".to_string())),
+                    name: Box::new(AstNode::Name("y".to_string(), Span::default())),
+                    annotation: None,
+                    doc: None,
                     expr: Box::new(AstNode::Double {
                         value: Value::Float(1.0),
-                        dimension: Unit {
-                            unit: Unit::Kilometer
+                        unit: Unit {
+                            unit: UnitIdentity::Meter(1000.0),
+                            quantity: Quantity::base(BaseDimension::Length, 1)
                         }
                     })
                 },
                 AstNode::Variable {
-                    name: Box::new(AstNode::Name("z".to_string())),
+                    name: Box::new(AstNode::Name("z".to_string(), Span::default())),
+                    annotation: None,
+                    doc: None,
                     expr: Box::new(AstNode::Expression {
                         operation: BinaryOperation::Add,
-                        lhs: Box::new(AstNode::Name("x".to_string())),
-                        rhs: Box::new(AstNode::Name("y".to_string()))
+                        lhs: Box::new(AstNode::Name("x".to_string(), Span::default())),
+                        rhs: Box::new(AstNode::Name("y".to_string(), Span::default())),
+                        span: Span::default(),
                     })
                 },
             ]
         ))
     );
 }
+
+#[test]
+fn test_parse_si_base_units() {
+    assert_eq!(
+        parse_number("2[kg^1]"),
+        Ok((
+            "",
+            AstNode::Double {
+                value: Value::Float(2.0),
+                unit: Unit {
+                    unit: UnitIdentity::Kilogram(1.0),
+                    quantity: Quantity::base(BaseDimension::Mass, 1)
+                }
+            }
+        ))
+    );
+    assert_eq!(
+        parse_number("3[mol^1]"),
+        Ok((
+            "",
+            AstNode::Double {
+                value: Value::Float(3.0),
+                unit: Unit {
+                    unit: UnitIdentity::Mole(1.0),
+                    quantity: Quantity::base(BaseDimension::AmountOfSubstance, 1)
+                }
+            }
+        ))
+    );
+
+    let (_, grams) = parse_length("[g^2]").unwrap();
+    assert_eq!(grams.quantity, Quantity::base(BaseDimension::Mass, 2));
+    assert_eq!(grams.unit, UnitIdentity::Gram(0.001f64.powi(2)));
+
+    for (alias, dimension) in [
+        ("[K^1]", BaseDimension::Temperature),
+        ("[kelvin^1]", BaseDimension::Temperature),
+        ("[A^1]", BaseDimension::ElectricCurrent),
+        ("[cd^1]", BaseDimension::LuminousIntensity),
+    ] {
+        let (_, unit) = parse_length(alias).unwrap();
+        assert_eq!(unit.quantity, Quantity::base(dimension, 1));
+    }
+}
+
+#[test]
+fn test_parse_derived_units() {
+    let (_, energy) = parse_length("[kWh^1]").unwrap();
+    assert_eq!(energy.unit, UnitIdentity::WattHour(3.6e6));
+    assert_eq!(energy.quantity, units::lookup("J").unwrap().quantity);
+
+    let (_, area) = parse_length("[hectares^1]").unwrap();
+    assert_eq!(area.quantity, Quantity::base(BaseDimension::Length, 2));
+}
+
+#[test]
+fn test_parse_unit_expressions() {
+    let length = Quantity::base(BaseDimension::Length, 1);
+    let time = Quantity::base(BaseDimension::Time, 1);
+
+    let (_, meters) = parse_length("[m]").unwrap();
+    assert_eq!(meters, parse_length("[m^1]").unwrap().1);
+
+    let (_, per_square_meter) = parse_length("[m^-2]").unwrap();
+    assert_eq!(per_square_meter.quantity, length.powi(-2).unwrap());
+
+    let (_, force) = parse_length("[kg*m/s^2]").unwrap();
+    assert_eq!(force.quantity, units::lookup("N").unwrap().quantity);
+    assert_eq!(force.get_conversion_factor(), 1.0);
+
+    let (_, energy) = parse_length("[kg·m^2/s^2]").unwrap();
+    assert_eq!(energy.quantity, units::lookup("J").unwrap().quantity);
+
+    let (_, wage) = parse_length("[USD/month]").unwrap();
+    assert_eq!(
+        wage.quantity,
+        (Quantity::base(BaseDimension::Currency, 1) / time).unwrap()
+    );
+    assert_eq!(wage.get_conversion_factor(), 1.0 / 2629746.0);
+
+    let (_, speed_squared) = parse_length("[(km / h)^2]").unwrap();
+    assert_eq!(
+        speed_squared.quantity,
+        (length / time).unwrap().powi(2).unwrap()
+    );
+    assert_eq!(
+        speed_squared.get_conversion_factor(),
+        (1000.0f64 / 3600.0).powi(2)
+    );
+
+    // left to right, so this is (m/s)*s
+    let (_, left_associative) = parse_length("[m/s*s]").unwrap();
+    assert_eq!(left_associative.quantity, length);
+}
+
+#[test]
+fn test_parse_program_errors() {
+    let error = parse_program("test.r2", "a = 1[m];\n\nb = 2[furlongs];").unwrap_err();
+    assert_eq!(error.location.line, 3);
+    assert_eq!(error.location.column, 7);
+    assert_eq!(error.message, Some("unknown unit `furlongs`".to_string()));
+    assert_eq!(
+        error.to_string(),
+        "error: unknown unit `furlongs`\n --> test.r2:3:7\n  |\n3 | b = 2[furlongs];\n  |       ^"
+    );
+
+    let error = parse_program("test.r2", "a = (1[m] + 2[m])").unwrap_err();
+    assert_eq!(error.location.column, 18);
+    assert_eq!(
+        error.expected,
+        vec!["`;`".to_string(), "an operator".to_string()]
+    );
+
+    let error = parse_program("test.r2", "a = (1[m] % 2[m]);").unwrap_err();
+    assert_eq!(error.location.column, 11);
+    assert_eq!(
+        error.expected,
+        vec!["`)`".to_string(), "an operator".to_string()]
+    );
+
+    let error = parse_program("test.r2", "a = 2[m] ^;").unwrap_err();
+    assert_eq!(error.location.column, 11);
+    assert!(error.expected.contains(&"a name".to_string()));
+
+    let error = parse_program("test.r2", "a = 1[m^x];").unwrap_err();
+    assert_eq!(error.location.column, 9);
+    assert_eq!(error.expected, vec!["an integer exponent".to_string()]);
+    for source in [
+        "a = 1[m^2000000000 * m^2000000000];",
+        "a = 1[(m^70000)^70000];",
+        "a = 1[L^1000000000];",
+    ] {
+        let error = parse_program("test.r2", source).unwrap_err();
+        assert_eq!(error.location.column, 7, "{}", source);
+        assert_eq!(
+            error.message,
+            Some("the exponents of the unit are too large".to_string()),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_parse_operator_precedence() {
+    let meters = |value: f64| AstNode::Double {
+        value: Value::Float(value),
+        unit: Unit {
+            unit: UnitIdentity::Meter(1.0),
+            quantity: Quantity::base(BaseDimension::Length, 1),
+        },
+    };
+    let name = |name: &str| AstNode::Name(name.to_string(), Span::default());
+    let expression = |operation, lhs, rhs| AstNode::Expression {
+        operation,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+        span: Span::default(),
+    };
+
+    // * binds tighter than +
+    let product_then_sum = expression(
+        BinaryOperation::Add,
+        expression(BinaryOperation::Multiply, meters(2.0), meters(3.0)),
+        meters(1.0),
+    );
+    assert_eq!(
+        spanless(parse_expression("2[m] * 3[m] + 1[m]")),
+        Ok(("", product_then_sum.clone()))
+    );
+    assert_eq!(
+        spanless(parse_expression("1[m] + 2[m] * 3[m]")),
+        Ok((
+            "",
+            expression(
+                BinaryOperation::Add,
+                meters(1.0),
+                expression(BinaryOperation::Multiply, meters(2.0), meters(3.0)),
+            )
+        ))
+    );
+
+    // redundant parentheses make no difference
+    assert_eq!(
+        spanless(parse_expression("((2[m] * 3[m])) + (1[m])")),
+        Ok(("", product_then_sum))
+    );
+
+    // equal precedence groups to the left
+    assert_eq!(
+        spanless(parse_expression("a - b - c")),
+        Ok((
+            "",
+            expression(
+                BinaryOperation::Subtract,
+                expression(BinaryOperation::Subtract, name("a"), name("b")),
+                name("c"),
+            )
+        ))
+    );
+    assert_eq!(
+        spanless(parse_expression("a / b * c")),
+        Ok((
+            "",
+            expression(
+                BinaryOperation::Multiply,
+                expression(BinaryOperation::Divide, name("a"), name("b")),
+                name("c"),
+            )
+        ))
+    );
+
+    // parentheses override precedence
+    assert_eq!(
+        spanless(parse_expression("a * (b + c)")),
+        Ok((
+            "",
+            expression(
+                BinaryOperation::Multiply,
+                name("a"),
+                expression(BinaryOperation::Add, name("b"), name("c")),
+            )
+        ))
+    );
+}
+
+#[test]
+fn test_parse_unary_minus() {
+    let negated = |node| AstNode::Expression {
+        operation: BinaryOperation::Multiply,
+        lhs: Box::new(AstNode::Double {
+            value: Value::Float(-1.0),
+            unit: Unit::dimensionless(),
+        }),
+        rhs: Box::new(node),
+        span: Span::default(),
+    };
+    let a = AstNode::Name("a".to_string(), Span::default());
+
+    assert_eq!(
+        spanless(parse_expression("-a")),
+        Ok(("", negated(a.clone())))
+    );
+    assert_eq!(
+        spanless(parse_expression("-(a)")),
+        Ok(("", negated(a.clone())))
+    );
+    assert_eq!(
+        spanless(parse_expression("2[m] - -a")),
+        Ok((
+            "",
+            AstNode::Expression {
+                operation: BinaryOperation::Subtract,
+                lhs: Box::new(AstNode::Double {
+                    value: Value::Float(2.0),
+                    unit: Unit {
+                        unit: UnitIdentity::Meter(1.0),
+                        quantity: Quantity::base(BaseDimension::Length, 1),
+                    },
+                }),
+                rhs: Box::new(negated(a)),
+                span: Span::default(),
+            }
+        ))
+    );
+
+    // a negative literal is still just a number
+    assert_eq!(parse_expression("-2[m]"), parse_number("-2[m]"));
+}
+
+#[test]
+fn test_parse_power() {
+    let name = |name: &str| AstNode::Name(name.to_string(), Span::default());
+    let expression = |operation, lhs, rhs| AstNode::Expression {
+        operation,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+        span: Span::default(),
+    };
+    let minus_one = AstNode::Double {
+        value: Value::Float(-1.0),
+        unit: Unit::dimensionless(),
+    };
+
+    // ^ binds tightest and groups to the right
+    assert_eq!(
+        spanless(parse_expression("x * a ^ b ^ c")),
+        Ok((
+            "",
+            expression(
+                BinaryOperation::Multiply,
+                name("x"),
+                expression(
+                    BinaryOperation::Power,
+                    name("a"),
+                    expression(BinaryOperation::Power, name("b"), name("c")),
+                ),
+            )
+        ))
+    );
+
+    // and tighter than negation
+    assert_eq!(
+        spanless(parse_expression("-a^b")),
+        Ok((
+            "",
+            expression(
+                BinaryOperation::Multiply,
+                minus_one,
+                expression(BinaryOperation::Power, name("a"), name("b")),
+            )
+        ))
+    );
+}
+
+#[test]
+fn test_parse_dimensionless() {
+    assert_eq!(
+        parse_value("2"),
+        Ok((
+            "",
+            AstNode::Double {
+                value: Value::Float(2.0),
+                unit: Unit::dimensionless(),
+            }
+        ))
+    );
+    assert_eq!(
+        parse_value("[1 2 3]"),
+        Ok((
+            "",
+            AstNode::Vector {
+                value: Value::Vec(vec![1.0, 2.0, 3.0]),
+                unit: Unit::dimensionless(),
+            }
+        ))
+    );
+    assert_eq!(
+        parse_value("15%"),
+        Ok((
+            "",
+            AstNode::Double {
+                value: Value::Float(0.15),
+                unit: Unit::dimensionless(),
+            }
+        ))
+    );
+    assert_eq!(
+        parse_value("[5 50]%"),
+        Ok((
+            "",
+            AstNode::Vector {
+                value: Value::Vec(vec![0.05, 0.5]),
+                unit: Unit::dimensionless(),
+            }
+        ))
+    );
+
+    // the same as spelling out the unit
+    assert_eq!(parse_value("10"), parse_value("10[none^1]"));
+}
+
+#[test]
+fn test_parse_conversion() {
+    let hours = Unit {
+        unit: UnitIdentity::Hour(3600.0),
+        quantity: Quantity::base(BaseDimension::Time, 1),
+    };
+    let (rest, conversion) = spanless(parse_expression("a / b as [h]")).unwrap();
+    assert_eq!(rest, "");
+    assert_eq!(
+        conversion,
+        AstNode::Conversion {
+            expr: Box::new(AstNode::Expression {
+                operation: BinaryOperation::Divide,
+                lhs: Box::new(AstNode::Name("a".to_string(), Span::default())),
+                rhs: Box::new(AstNode::Name("b".to_string(), Span::default())),
+                span: Span::default(),
+            }),
+            unit: hours,
+            span: Span::default(),
+        }
+    );
+    assert_eq!(
+        spanless(parse_expression("a / b in [h]")),
+        Ok(("", conversion))
+    );
+
+    // `as` has to be followed by a unit
+    let error = parse_program("test.r2", "a = b as h;").unwrap_err();
+    assert_eq!(error.location.column, 10);
+    assert_eq!(error.expected, vec!["`[`".to_string()]);
+
+    // a longer word is not the keyword
+    let error = parse_program("test.r2", "a = b ash;").unwrap_err();
+    assert_eq!(error.location.column, 7);
+    assert_eq!(
+        error.to_string().lines().next(),
+        Some("error: expected `;` or an operator")
+    );
+}
+
+#[test]
+fn test_parse_annotation() {
+    let (_, variable) = parse_variable("wage[USD/month] = 2000[USD/month];").unwrap();
+    let AstNode::Variable {
+        annotation: Some(Annotation::Unit(unit)),
+        ..
+    } = variable
+    else {
+        panic!("expected a unit annotation, found {:?}", variable);
+    };
+    assert_eq!(unit.get_conversion_factor(), 1.0 / 2629746.0);
+
+    let (_, variable) = parse_variable("benefit[currency*time] = 1[USD*h];").unwrap();
+    assert!(matches!(
+        variable,
+        AstNode::Variable {
+            annotation: Some(Annotation::Dimension(quantity)),
+            ..
+        } if quantity == Quantity::from_powers(&[(BaseDimension::Currency, 1), (BaseDimension::Time, 1)])
+    ));
+
+    // units and dimensions can be mixed, [USD/time]
+    let (_, variable) = parse_variable("rate[USD/time] = 1[USD/h];").unwrap();
+    assert!(matches!(
+        variable,
+        AstNode::Variable {
+            annotation: Some(Annotation::Dimension(_)),
+            ..
+        }
+    ));
+
+    // but dimensions are only names in annotations
+    let error = parse_program("test.r2", "a = 1[time];").unwrap_err();
+    assert_eq!(error.message, Some("unknown unit `time`".to_string()));
+
+    let error = parse_program("test.r2", "a[furlongs] = 1;").unwrap_err();
+    assert_eq!(
+        error.message,
+        Some("unknown unit or dimension `furlongs`".to_string())
+    );
+    assert_eq!(error.location.column, 3);
+}
+
+#[test]
+fn test_parse_spans() {
+    // spans count from the end of the source, "a + (b * c) as [m]" is 18 long
+    let (_, conversion) = parse_expression("a + (b * c) as [m]").unwrap();
+    let name = |name: &str, from_end| AstNode::Name(name.to_string(), Span { from_end });
+    assert_eq!(
+        conversion,
+        AstNode::Conversion {
+            expr: Box::new(AstNode::Expression {
+                operation: BinaryOperation::Add,
+                lhs: Box::new(name("a", 18)),
+                rhs: Box::new(AstNode::Expression {
+                    operation: BinaryOperation::Multiply,
+                    lhs: Box::new(name("b", 13)),
+                    rhs: Box::new(name("c", 9)),
+                    span: Span { from_end: 14 },
+                }),
+                span: Span { from_end: 18 },
+            }),
+            unit: units::resolve("m", 1).unwrap(),
+            span: Span { from_end: 6 },
+        }
+    );
+    assert_ne!(
+        parse_expression("a + b").unwrap().1,
+        parse_expression("a  + b").unwrap().1
+    );
+}
+
+#[test]
+fn test_parse_single_expression() {
+    assert_eq!(
+        parse_single_expression("<eval>", " 2[km] / 30[min] as [km/h]\n")
+            .map(AstNode::without_spans),
+        Ok(spanless(parse_expression("2[km] / 30[min] as [km/h]"))
+            .unwrap()
+            .1)
+    );
+
+    let error = parse_single_expression("<eval>", "2[km] 30[min]").unwrap_err();
+    assert_eq!(error.location.column, 7);
+    assert_eq!(error.expected, vec!["an operator".to_string()]);
+
+    let error = parse_single_expression("<eval>", "a = 1;").unwrap_err();
+    assert_eq!(error.location.column, 3);
+}
+
+#[test]
+fn test_parse_csv() {
+    let (rest, column) = spanless(parse_expression("csv{height_of_side[km]} * 2")).unwrap();
+    assert_eq!(rest, "");
+    assert_eq!(
+        column,
+        AstNode::Expression {
+            operation: BinaryOperation::Multiply,
+            lhs: Box::new(AstNode::Csv {
+                column: "height_of_side".to_string(),
+                unit: units::resolve("km", 1).unwrap(),
+                span: Span::default(),
+            }),
+            rhs: Box::new(AstNode::Double {
+                value: Value::Float(2.0),
+                unit: Unit::dimensionless(),
+            }),
+            span: Span::default(),
+        }
+    );
+    assert_eq!(
+        spanless(parse_expression("csv{ count }")),
+        Ok((
+            "",
+            AstNode::Csv {
+                column: "count".to_string(),
+                unit: Unit::dimensionless(),
+                span: Span::default(),
+            }
+        ))
+    );
+
+    // `csv` without a brace is not a name either
+    assert!(matches!(
+        spanless(parse_expression("csv + 1")),
+        Err(nom::Err::Failure(SyntaxError { message: Some(message), .. }))
+            if message == "`csv` is a reserved word, so it cannot be used in a name"
+    ));
+
+    let error = parse_program("test.r2", "a = csv{height[km];").unwrap_err();
+    assert_eq!(error.location.column, 19);
+    assert_eq!(error.expected, vec!["`}`".to_string()]);
+    let error = parse_program("test.r2", "a = csv{[km]};").unwrap_err();
+    assert_eq!(error.expected, vec!["a column name".to_string()]);
+}
+
+#[test]
+fn test_display_expression() {
+    for source in [
+        "(a + b) * c",
+        "a - (b - c)",
+        "a - b - c",
+        "a^b^c",
+        "(a^b)^c",
+        "2[km] / (30[min] + t) as [km/h]",
+        "[1 2.5][m^2] * csv{cost[USD/month]}",
+        "(a as [m]) * 2",
+        "-a^2 * b",
+        "(-a)^2",
+        "a * -(b + c)",
+        "-1 * 2[m]",
+        "(-2)^2",
+        "(-3[m])^2",
+        "-2^2",
+        "2^-1",
+        "(-a)^-b",
+    ] {
+        let (rest, expression) = parse_expression(source).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(expression.to_string(), source);
+    }
+
+    let (_, statements) = parse_statements("wage[currency/time] = 12[USD] / 4[month];").unwrap();
+    assert_eq!(
+        statements[0].to_string(),
+        "wage[currency/time] = 12[USD] / 4[month];"
+    );
+}
+
+#[test]
+fn test_parse_multi_line_statements() {
+    let source = "wage = 12[USD] / 4[month]; absence = 3[day];\n\
+                  benefit = (wage\n    * absence)\n  * 0.5;\n";
+    let program = parse_program("test.r2", source).unwrap();
+    assert_eq!(program.len(), 2);
+    assert_eq!(program[0].len(), 2);
+    assert_eq!(program[1][0].to_string(), "benefit = wage * absence * 0.5;");
+
+    // spans still point into the right line
+    let AstNode::Variable { expr, .. } = &program[1][0] else {
+        panic!("expected a variable");
+    };
+    let AstNode::Expression { lhs, .. } = expr.as_ref() else {
+        panic!("expected an expression");
+    };
+    let AstNode::Expression { rhs, .. } = lhs.as_ref() else {
+        panic!("expected an expression");
+    };
+    let AstNode::Name(name, span) = rhs.as_ref() else {
+        panic!("expected a name");
+    };
+    assert_eq!(name, "absence");
+    assert_eq!(position(*span, source), (3, 7, "    * absence)"));
+
+    // a forgotten `;` is reported at the end of its line
+    let error = parse_program("test.r2", "a = 1[m]\nb = 2[m];").unwrap_err();
+    assert_eq!((error.location.line, error.location.column), (1, 9));
+    assert_eq!(
+        error.expected,
+        vec!["`;`".to_string(), "an operator".to_string()]
+    );
+
+    let error = parse_program("test.r2", "a = (1[m] +\n  2[furlongs]);").unwrap_err();
+    assert_eq!((error.location.line, error.location.column), (2, 5));
+}
+
+#[test]
+fn test_parse_comments() {
+    let source = "# inputs\n\
+                  ## what a month of work pays\n\
+                  ##   before tax\n\
+                  wage = 12[USD] / # per\n  4[month]; /* a block\n comment */ absence = 3[day];\n\
+                  \n\
+                  ## not attached to anything\n";
+    let program = parse_program("test.r2", source).unwrap();
+    let statements: Vec<&AstNode> = program.iter().flatten().collect();
+    assert_eq!(statements.len(), 2);
+    assert!(matches!(
+        statements[0],
+        AstNode::Variable { doc: Some(doc), .. } if doc == "what a month of work pays\n  before tax"
+    ));
+    assert!(matches!(statements[1], AstNode::Variable { doc: None, .. }));
+    assert_eq!(
+        statements[0].to_string(),
+        "## what a month of work pays\n##   before tax\nwage = 12[USD] / 4[month];"
+    );
+
+    // comments go wherever whitespace does
+    assert_eq!(
+        parse_expression("(1[m] /* one */ + # two\n 2[m]) as /**/ [km]")
+            .unwrap()
+            .1
+            .to_string(),
+        "1[m] + 2[m] as [km]"
+    );
+
+    let error = parse_program("test.r2", "a = 1[m]; /* never\nclosed").unwrap_err();
+    assert_eq!((error.location.line, error.location.column), (1, 11));
+    assert_eq!(
+        error.message,
+        Some("unterminated block comment, expected `*/`".to_string())
+    );
+}
+
+#[test]
+fn test_parse_names() {
+    for name in [
+        "avoided_days_of_lost_due_to_anxiety",
+        "q1_revenue",
+        "_private",
+        "costs.labour",
+        "model.costs.q2",
+    ] {
+        assert_eq!(
+            spanless(parse_name(name)),
+            Ok(("", AstNode::Name(name.to_string(), Span::default())))
+        );
+    }
+    // a name cannot start with a digit, or end with a dot
+    assert!(parse_name("1st").is_err());
+    assert_eq!(
+        spanless(parse_name("costs.")),
+        Ok((".", AstNode::Name("costs".to_string(), Span::default())))
+    );
+
+    let error = parse_program("test.r2", "costs.in = 1;").unwrap_err();
+    assert_eq!(error.location.column, 1);
+    assert_eq!(
+        error.message,
+        Some("`in` is a reserved word, so it cannot be used in a name".to_string())
+    );
+
+    let error = parse_program("test.r2", "a = 1;\nminutes = 2[min];").unwrap_err();
+    assert_eq!((error.location.line, error.location.column), (2, 1));
+    assert_eq!(
+        error.message,
+        Some("`minutes` is the unit `min`, so it cannot be a variable name".to_string())
+    );
+    assert!(parse_program("test.r2", "kms = 1[km]; mm = 1;").is_ok());
+}
+
+#[test]
+fn test_parse_formatting_variants() {
+    let statements = |source: &str| -> Vec<AstNode> {
+        parse_program("test.r2", source)
+            .unwrap_or_else(|error| panic!("{:?} should parse: {}", source, error))
+            .into_iter()
+            .flatten()
+            .map(AstNode::without_spans)
+            .collect()
+    };
+
+    // each group is one program written several ways
+    let corpus: &[&[&str]] = &[
+        &[
+            "a = 1[m];",
+            "a=1[m];",
+            "a  =  1[m] ;",
+            "a\t=\n1 [ m ];",
+            "a /* here */ = 1[m]; # there",
+        ],
+        &[
+            "speed[length/time] = 150[km] / 2[h] as [km/h];",
+            "speed[length/time]=150[km]/2[h]as[km/h];",
+            "speed [ length / time ] = 150 [km]\n  / 2 [h]\n  as [km/h];",
+            "speed[length/time] =\n    150[km] / 2[h] # per hour\n    as [ km / h ];",
+        ],
+        &[
+            "f = (a + b) * -c ^ 2;",
+            "f=(a+b)*-c^2;",
+            "f = ( a + b ) * - c ^ 2 ;",
+            "f =\n(\n  a +\n  b\n)\n* -c\n^ 2;",
+        ],
+        &[
+            "area = [1 2.5][m^2] * 3%;",
+            "area=[1 2.5][m^2]*3%;",
+            "area = [ 1  2.5 ] [ m ^ 2 ] * 3 %;",
+        ],
+        &[
+            "x = csv{height[km]} in [m];",
+            "x=csv{height[km]}in[m];",
+            "x = csv { height [ km ] } in [ m ] ;",
+        ],
+        &[
+            "## the floor\nroom.area = 2[km] * 3[m];\nfloor = room.area;",
+            "## the floor\nroom.area=2[km]*3[m];floor=room.area;",
+            "## the floor\n  room.area = 2[km]\n    * 3[m];\n\n\nfloor = room.area ;\n",
+        ],
+    ];
+
+    for variants in corpus {
+        let expected = statements(variants[0]);
+        for variant in &variants[1..] {
+            assert_eq!(statements(variant), expected, "{:?}", variant);
+        }
+        // and the program as it is displayed parses back to the same tree
+        let displayed: Vec<String> = expected.iter().map(AstNode::to_string).collect();
+        assert_eq!(statements(&displayed.join("\n")), expected);
+    }
+}
@@ -0,0 +1,477 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use super::csv::Table;
+use super::interpreter::{Interpreter, Memory};
+use super::parser::{parse_program, parse_single_expression};
+use super::types::{AstNode, DimensionedValue};
+
+const PROMPT: &str = "r2> ";
+const CONTINUATION_PROMPT: &str = "..> ";
+const INPUT_NAME: &str = "<repl>";
+
+pub const HELP: &str = "enter a statement, `a = 2[km];`, or an expression, `a / 30[min] as [km/h]`
+  :vars         list every variable
+  :clear        forget every variable, keeping the csv files
+  :load FILE    run a program, keeping its variables
+  :save FILE    write the statements run so far to FILE
+  :history      list what has been entered
+  !N            enter history entry N again
+  :help         show this message
+  :quit         leave, as does end of input";
+
+/// More opening than closing brackets outside comments, or a `/*` comment
+/// that is not closed, so the input goes on to the next line
+fn is_unbalanced(input: &str) -> bool {
+    let mut code = String::new();
+    let mut rest = input;
+    loop {
+        match (rest.find('#'), rest.find("/*")) {
+            (Some(line), block) if block.is_none_or(|block| line < block) => {
+                code.push_str(&rest[..line]);
+                rest = rest[line..].split_once('\n').map_or("", |(_, after)| after);
+            }
+            (_, Some(block)) => {
+                code.push_str(&rest[..block]);
+                match rest[block + 2..].split_once("*/") {
+                    Some((_, after)) => rest = after,
+                    None => return true,
+                }
+            }
+            (_, None) => {
+                code.push_str(rest);
+                break;
+            }
+        }
+    }
+
+    let depth = |open: char, close: char| {
+        code.matches(open).count() as isize - code.matches(close).count() as isize
+    };
+    depth('(', ')') > 0 || depth('[', ']') > 0
+}
+
+/// `count` things, with the plural of `thing` unless there is one
+fn plural(count: usize, thing: &str) -> String {
+    match count {
+        1 => format!("1 {}", thing),
+        count => format!("{} {}s", count, thing),
+    }
+}
+
+/// An interactive session. Variables persist between inputs, and the source
+/// of every statement that ran is kept so the session can be saved
+pub struct Repl {
+    memory: Memory,
+    /// the statements that ran, as they were written
+    source: Vec<String>,
+    pub history: Vec<String>,
+    history_file: Option<PathBuf>,
+    /// print values in a matching derived unit, J rather than kg·m²/s²
+    derived_units: bool,
+}
+
+impl Repl {
+    /// A session with no variables. Inputs are appended to `history_file`,
+    /// and the history already in it is loaded
+    pub fn new(history_file: Option<PathBuf>) -> Repl {
+        let history = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|history| history.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        Repl {
+            memory: Interpreter::new(vec![]),
+            source: vec![],
+            history,
+            history_file,
+            derived_units: false,
+        }
+    }
+
+    /// The same session, reading `csv{column}` from `tables`
+    pub fn with_tables(mut self, tables: Vec<Table>) -> Repl {
+        self.memory = self.memory.with_tables(tables);
+        self
+    }
+
+    /// The same session, printing values in derived units when `derived_units`
+    pub fn with_derived_units(mut self, derived_units: bool) -> Repl {
+        self.derived_units = derived_units;
+        self
+    }
+
+    /// `value` as it is printed
+    fn shown(&self, value: &DimensionedValue) -> DimensionedValue {
+        if self.derived_units {
+            value.in_derived_units()
+        } else {
+            value.clone()
+        }
+    }
+
+    /// Read inputs until the end of `input` or `:quit`, writing prompts,
+    /// results and errors to `output`
+    pub fn run(&mut self, input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        let mut pending = String::new();
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            if !pending.is_empty() {
+                pending.push('\n');
+            }
+            pending.push_str(line.trim());
+
+            if is_unbalanced(&pending) {
+                write!(output, "{}", CONTINUATION_PROMPT)?;
+                output.flush()?;
+                continue;
+            }
+
+            let mut entry = std::mem::take(&mut pending);
+            // `!N` is entry N of the history, entered again
+            if let Some(Ok(number)) = entry.strip_prefix('!').map(str::parse::<usize>) {
+                match number
+                    .checked_sub(1)
+                    .and_then(|index| self.history.get(index))
+                {
+                    Some(recalled) => {
+                        entry = recalled.clone();
+                        writeln!(output, "{}", entry)?;
+                    }
+                    None => {
+                        writeln!(output, "error: no history entry {}", number)?;
+                        entry.clear();
+                    }
+                }
+            }
+            if !entry.is_empty() {
+                // the history file has an entry per line
+                self.remember(&entry.replace('\n', " "));
+                if entry == ":quit" {
+                    return Ok(());
+                }
+                self.handle(&entry, output)?;
+            }
+            write!(output, "{}", PROMPT)?;
+            output.flush()?;
+        }
+
+        writeln!(output)
+    }
+
+    fn remember(&mut self, entry: &str) {
+        self.history.push(entry.to_string());
+        if let Some(path) = &self.history_file {
+            // history is a convenience, failing to write it is not an error
+            let _ = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", entry));
+        }
+    }
+
+    /// Handle one complete input: a command, statements ending in `;`, or an
+    /// expression whose value is printed
+    pub fn handle(&mut self, entry: &str, output: &mut impl Write) -> io::Result<()> {
+        if let Some(command) = entry.strip_prefix(':') {
+            return self.command(command, output);
+        }
+        // only statements have a `;`, though a comment may follow it
+        if entry.contains(';') {
+            return self.statements(INPUT_NAME, entry, output);
+        }
+
+        let expression = match parse_single_expression(INPUT_NAME, entry) {
+            Ok(expression) => expression,
+            Err(error) => return writeln!(output, "{}", error),
+        };
+        match self.memory.evaluate(expression) {
+            Ok(value) => writeln!(output, "{}", self.shown(&value)),
+            Err(error) => writeln!(output, "{}", error.render(INPUT_NAME, entry)),
+        }
+    }
+
+    /// Run statements, printing the value of each variable they assign. When
+    /// one fails none of them are kept, so the saved source matches memory
+    fn statements(
+        &mut self,
+        file_name: &str,
+        source: &str,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let program = match parse_program(file_name, source) {
+            Ok(program) => program,
+            Err(error) => return writeln!(output, "{}", error),
+        };
+
+        let statements: Vec<AstNode> = program.into_iter().flatten().collect();
+        let assigned: Vec<String> = statements
+            .iter()
+            .filter_map(|statement| match statement {
+                AstNode::Variable { name, .. } => match name.as_ref() {
+                    AstNode::Name(name, _) => Some(name.clone()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+
+        let before = self.memory.variables().len();
+        match self.memory.execute_all(statements) {
+            Ok(()) => {
+                self.source.push(source.trim_end().to_string());
+                if file_name == INPUT_NAME {
+                    for name in assigned {
+                        let value = self.shown(&self.memory.memory[&name]);
+                        writeln!(output, "{} = {}", name, value)?;
+                    }
+                } else {
+                    let count = self.memory.variables().len() - before;
+                    let variables = plural(count, "new variable");
+                    writeln!(output, "loaded {} from {}", variables, file_name)?;
+                }
+                Ok(())
+            }
+            Err(error) => writeln!(output, "{}", error.render(file_name, source)),
+        }
+    }
+
+    fn command(&mut self, command: &str, output: &mut impl Write) -> io::Result<()> {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+
+        match (name, argument) {
+            ("vars", "") => {
+                for (name, value) in self.memory.variables() {
+                    writeln!(output, "{} = {}", name, self.shown(value))?;
+                }
+                Ok(())
+            }
+            ("clear", "") => {
+                self.memory.clear();
+                self.source.clear();
+                Ok(())
+            }
+            ("load", file) if !file.is_empty() => match fs::read_to_string(file) {
+                Ok(source) => self.statements(file, &source, output),
+                Err(error) => writeln!(output, "error: cannot read {}: {}", file, error),
+            },
+            ("save", file) if !file.is_empty() => {
+                let mut source = self.source.join("\n");
+                source.push('\n');
+                match fs::write(file, source) {
+                    Ok(()) => {
+                        let statements = plural(self.source.len(), "statement");
+                        writeln!(output, "saved {} to {}", statements, file)
+                    }
+                    Err(error) => writeln!(output, "error: cannot write {}: {}", file, error),
+                }
+            }
+            ("history", "") => {
+                for (index, entry) in self.history.iter().enumerate() {
+                    writeln!(output, "{:>4}  {}", index + 1, entry)?;
+                }
+                Ok(())
+            }
+            ("help", "") => writeln!(output, "{}", HELP),
+            ("load" | "save", _) => writeln!(output, "error: `:{}` takes a FILE", name),
+            _ => writeln!(output, "error: unknown command `:{}`, try :help", command),
+        }
+    }
+}
+
+#[cfg(test)]
+fn session(repl: &mut Repl, input: &str) -> String {
+    let mut output = vec![];
+    repl.run(input.as_bytes(), &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_repl_statements_and_expressions() {
+    let mut repl = Repl::new(None);
+    let output = session(
+        &mut repl,
+        "distance = 150[km];\nspeed = 60[km/h]; time = distance / speed as [h];\ntime * 2\n:vars\n",
+    );
+    assert!(output.contains("distance = 150 km\n"));
+    assert!(output.contains("speed = 60 km/h\ntime = 2.5 h\n"));
+    assert!(output.contains("\nr2> 18000 s\n"));
+    assert!(output.contains("r2> distance = 150 km\nspeed = 60 km/h\ntime = 2.5 h\n"));
+
+    // variables persist between sessions on the same repl
+    let output = session(&mut repl, "speed\n:clear\nspeed\n");
+    assert!(output.starts_with("r2> 60 km/h\n"));
+    assert!(output.contains("error: undefined variable `speed`"));
+}
+
+#[test]
+fn test_repl_derived_units() {
+    let input = "force = 2[kg*m/s^2];
+force * 3[m]
+";
+    let output = session(&mut Repl::new(None), input);
+    assert!(output.contains(
+        "force = 2 kg·m/s²
+"
+    ));
+    assert!(output.contains(
+        "r2> 6 kg·m²/s²
+"
+    ));
+
+    let output = session(&mut Repl::new(None).with_derived_units(true), input);
+    assert!(output.contains(
+        "force = 2 N
+"
+    ));
+    assert!(output.contains(
+        "r2> 6 J
+"
+    ));
+}
+
+#[test]
+fn test_repl_multi_line_input() {
+    let mut repl = Repl::new(None);
+    let output = session(&mut repl, "a = (1[m]\n+ 2[m]\n);\n");
+    assert!(output.starts_with("r2> ..> ..> a = 3 m\n"));
+    assert_eq!(repl.history, vec!["a = (1[m] + 2[m] );".to_string()]);
+
+    // brackets in comments do not count
+    let output = session(
+        &mut repl,
+        "b = 1; # note (
+b
+",
+    );
+    assert!(output.starts_with(
+        "r2> b = 1
+r2> 1
+"
+    ));
+    let output = session(
+        &mut repl,
+        "c = /* ( */ 2;
+c = (1 /* ) */
+);
+",
+    );
+    assert!(output.starts_with(
+        "r2> c = 2
+r2> ..> c = 1
+"
+    ));
+    assert!(!is_unbalanced("a = 1; ## see [1"));
+    assert!(is_unbalanced("a = 1; /* unfinished"));
+}
+
+#[test]
+fn test_repl_recall_history() {
+    let mut repl = Repl::new(None);
+    let output = session(
+        &mut repl,
+        "a = 2[m];
+a * 3
+!2
+!1
+!7
+!x
+",
+    );
+    assert!(output.contains(
+        "r2> 6 m
+r2> a * 3
+6 m
+"
+    ));
+    assert!(output.contains(
+        "r2> a = 2[m];
+a = 2 m
+"
+    ));
+    assert!(output.contains(
+        "r2> error: no history entry 7
+"
+    ));
+    assert!(output.contains("r2> error: expected"));
+    // what ran is remembered, not how it was asked for
+    assert_eq!(
+        repl.history,
+        vec!["a = 2[m];", "a * 3", "a * 3", "a = 2[m];", "!x"]
+    );
+}
+
+#[test]
+fn test_repl_errors_keep_going() {
+    let mut repl = Repl::new(None);
+    let output = session(
+        &mut repl,
+        "a = 1[m] + 1[s];\n1[furlong]\n:bogus\na = 1[m];\n",
+    );
+    assert!(output.contains("error: cannot add `m` and `s`"));
+    assert!(output.contains("error: unknown unit `furlong`"));
+    assert!(output.contains("error: unknown command `:bogus`"));
+    assert!(output.ends_with("a = 1 m\nr2> \n"));
+
+    // a failing statement undoes the ones before it in the same entry
+    let output = session(&mut repl, "b = 2; c = 1[m] + 1[s];\n:vars\n");
+    assert!(output.ends_with("r2> a = 1 m\nr2> \n"));
+
+    // a name that is also a unit is an error
+    let output = session(&mut repl, "d = 2;\n");
+    assert!(output.contains("error: `d` is the unit `day`, so it cannot be a variable name"));
+}
+
+#[test]
+fn test_repl_save_and_load() {
+    let path = std::env::temp_dir().join(format!("r2engine-repl-{}.r2", std::process::id()));
+    let file = path.to_str().unwrap();
+
+    let mut repl = Repl::new(None);
+    let output = session(
+        &mut repl,
+        &format!(
+            "a = 2[km];\nc = 1; b = 1[m] + 1[s];\na * 2\nb = a * 3;\n:save {}\n",
+            file
+        ),
+    );
+    assert!(output.contains(&format!("saved 2 statements to {}", file)));
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "a = 2[km];\nb = a * 3;\n"
+    );
+
+    let mut repl = Repl::new(None);
+    let output = session(&mut repl, &format!(":load {}\nb\n", file));
+    assert!(output.contains(&format!("loaded 2 new variables from {}", file)));
+    assert!(output.contains("r2> 6000 m\n"));
+
+    // loading it again only adds what is new
+    fs::write(&path, "a = 1[km];\nb = 2;\nc = 3;\n").unwrap();
+    let output = session(&mut repl, &format!(":load {}\n", file));
+    assert!(output.contains(&format!("loaded 1 new variable from {}", file)));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_repl_history_file() {
+    let path = std::env::temp_dir().join(format!("r2engine-history-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut repl = Repl::new(Some(path.clone()));
+    session(&mut repl, "a = 1;\n:vars\n:quit\nb = 2;\n");
+
+    let repl = Repl::new(Some(path.clone()));
+    assert_eq!(repl.history, vec!["a = 1;", ":vars", ":quit"]);
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// What a trace event is about, each can be switched on by itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    /// each parser rule as it is tried, and the input it is tried on
+    Parse,
+    /// each variable stored, each arithmetic operation and its result
+    Eval,
+    /// each unit parsed, and the unit derived by each operation
+    Units,
+}
+
+impl Category {
+    pub const ALL: [Category; 3] = [Category::Parse, Category::Eval, Category::Units];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Parse => "parse",
+            Category::Eval => "eval",
+            Category::Units => "units",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Category> {
+        Category::ALL
+            .into_iter()
+            .find(|category| category.name() == name)
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The enabled categories, one bit each. Nothing is traced by default
+static ENABLED: AtomicU8 = AtomicU8::new(0);
+
+pub fn enable(category: Category) {
+    ENABLED.fetch_or(category.bit(), Ordering::Relaxed);
+}
+
+pub fn is_enabled(category: Category) -> bool {
+    ENABLED.load(Ordering::Relaxed) & category.bit() != 0
+}
+
+/// A field value, quoted when it would not read back as one word
+fn format_field(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("{:?}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// One event as a line of `key=value` fields:
+///
+/// ```text
+/// [eval] operation op=/ lhs="2 km" rhs="30 min" result="1.111111111 m/s"
+/// ```
+pub fn format_event(
+    category: Category,
+    event: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+) -> String {
+    let mut line = format!("[{}] {}", category.name(), event);
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, format_field(&value.to_string())));
+    }
+    line
+}
+
+/// Write an event to stderr when its category is enabled. The fields are
+/// only formatted when it is, so tracing costs nothing when it is off
+pub fn event(category: Category, event: &str, fields: &[(&str, &dyn fmt::Display)]) {
+    if is_enabled(category) {
+        eprintln!("{}", format_event(category, event, fields));
+    }
+}
+
+#[test]
+fn test_category_names() {
+    for category in Category::ALL {
+        assert_eq!(Category::from_name(category.name()), Some(category));
+    }
+    assert_eq!(Category::from_name("lexer"), None);
+}
+
+#[test]
+fn test_format_event() {
+    assert_eq!(
+        format_event(
+            Category::Eval,
+            "store",
+            &[("name", &"distance"), ("value", &"150 km")]
+        ),
+        "[eval] store name=distance value=\"150 km\""
+    );
+    assert_eq!(
+        format_event(Category::Units, "unit", &[("alias", &"km"), ("power", &2)]),
+        "[units] unit alias=km power=2"
+    );
+    assert_eq!(
+        format_event(Category::Parse, "parse_variable", &[("input", &"")]),
+        "[parse] parse_variable input=\"\""
+    );
+}
@@ -1,394 +1,301 @@
+use std::fmt;
 use std::ops::{Add, Div, Mul, Sub};
 
+use super::error::EvalError;
+use super::units;
+
 #[derive(PartialEq, PartialOrd, Debug, Clone)]
 pub enum BinaryOperation {
     Add,
     Subtract,
     Multiply,
     Divide,
+    Power,
+}
+
+impl BinaryOperation {
+    /// The operator as it is written, `+` for Add
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperation::Add => "+",
+            BinaryOperation::Subtract => "-",
+            BinaryOperation::Multiply => "*",
+            BinaryOperation::Divide => "/",
+            BinaryOperation::Power => "^",
+        }
+    }
+
+    /// How tightly an operator binds, higher binds tighter
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOperation::Add | BinaryOperation::Subtract => 1,
+            BinaryOperation::Multiply | BinaryOperation::Divide => 2,
+            BinaryOperation::Power => 3,
+        }
+    }
 }
 
-/// The f64 type is the conversion factor to base units
+/// The f64 type is the conversion factor to base units, including any SI prefix
+/// and power, so 1[km^2] is Meter(1000000.0) with a Length^2 quantity
 #[derive(PartialEq, PartialOrd, Debug, Clone)]
 pub enum UnitIdentity {
-    CompoundUnit {
-        operation: BinaryOperation,
-        lhs: Box<UnitIdentity>,
-        rhs: Box<UnitIdentity>,
-    },
+    /// The combination of base units described by the quantity, e.g. m^1 s^-1
+    /// for a velocity, scaled by the factor when it comes from a unit
+    /// expression like [km/h]
+    Base(f64),
+    /// A unit expression like [km/h], with the symbol and power of each unit in
+    /// it, so that it prints the way it was written
+    Compound(f64, Vec<(String, i32)>),
     None(f64),
     Second(f64),
     Minute(f64), // Minute(60): x[m] * 60 == x[s]
     Hour(f64),
     Day(f64),
+    Week(f64),
+    Month(f64),
+    Year(f64),
     Meter(f64),
-    Kilometer(f64),
-    SquareMeter(f64),
-    SquareKilometer(f64),
-    CubicMeter(f64),
-    CubicKilometer(f64),
+    Newton(f64),
+    Joule(f64),
+    Watt(f64),
+    Pascal(f64),
+    Hertz(f64),
+    WattHour(f64),
+    Litre(f64),
+    Hectare(f64),
+    Gram(f64),
+    Kilogram(f64),
+    Kelvin(f64),
+    Mole(f64),
+    Ampere(f64),
+    Candela(f64),
     USD(f64),
     GBP(f64),
 }
 
-#[derive(PartialEq, PartialOrd, Debug, Clone)]
-pub enum Quantity {
-    CompoundQuantity {
-        operation: BinaryOperation,
-        lhs: Box<Quantity>,
-        rhs: Box<Quantity>,
-    },
-    None(i64),
-    Length(i64),
-    //    Mass(i64),
-    Time(i64),
-    Currency(i64),
+/// The independent dimensions every quantity is built from
+#[derive(PartialEq, Eq, PartialOrd, Debug, Clone, Copy)]
+pub enum BaseDimension {
+    Length,
+    Mass,
+    Time,
+    Temperature,
+    AmountOfSubstance,
+    ElectricCurrent,
+    LuminousIntensity,
+    Currency,
+}
+
+impl BaseDimension {
+    pub const ALL: [BaseDimension; 8] = [
+        BaseDimension::Length,
+        BaseDimension::Mass,
+        BaseDimension::Time,
+        BaseDimension::Temperature,
+        BaseDimension::AmountOfSubstance,
+        BaseDimension::ElectricCurrent,
+        BaseDimension::LuminousIntensity,
+        BaseDimension::Currency,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The name a dimension is written as in an annotation, wage[currency/time]
+    pub fn name(self) -> &'static str {
+        match self {
+            BaseDimension::Length => "length",
+            BaseDimension::Mass => "mass",
+            BaseDimension::Time => "time",
+            BaseDimension::Temperature => "temperature",
+            BaseDimension::AmountOfSubstance => "amount",
+            BaseDimension::ElectricCurrent => "current",
+            BaseDimension::LuminousIntensity => "luminosity",
+            BaseDimension::Currency => "currency",
+        }
+    }
+
+    /// Symbol of the SI (or, for currency, reference) unit of this dimension
+    pub fn base_symbol(self) -> &'static str {
+        match self {
+            BaseDimension::Length => "m",
+            BaseDimension::Mass => "kg",
+            BaseDimension::Time => "s",
+            BaseDimension::Temperature => "K",
+            BaseDimension::AmountOfSubstance => "mol",
+            BaseDimension::ElectricCurrent => "A",
+            BaseDimension::LuminousIntensity => "cd",
+            BaseDimension::Currency => "USD",
+        }
+    }
+}
+
+/// A quantity is the vector of integer exponents over the base dimensions:
+/// m/s is Length^1 Time^-1, m^2 is Length^2, a plain number has all zeros
+#[derive(PartialEq, Eq, PartialOrd, Debug, Clone, Copy, Default)]
+pub struct Quantity {
+    exponents: [i32; BaseDimension::ALL.len()],
 }
 
 impl Quantity {
+    pub fn dimensionless() -> Quantity {
+        Quantity::default()
+    }
+
+    /// A single base dimension raised to `power`, e.g. Length^2
+    pub fn base(dimension: BaseDimension, power: i32) -> Quantity {
+        Quantity::from_powers(&[(dimension, power)])
+    }
+
+    /// Base dimensions raised to powers, kg·m²/s² is
+    /// [(Mass, 1), (Length, 2), (Time, -2)]
+    pub fn from_powers(powers: &[(BaseDimension, i32)]) -> Quantity {
+        let mut quantity = Quantity::dimensionless();
+        for (dimension, power) in powers {
+            quantity.exponents[dimension.index()] = *power;
+        }
+        quantity
+    }
+
+    pub fn exponent(&self, dimension: BaseDimension) -> i32 {
+        self.exponents[dimension.index()]
+    }
+
+    pub fn is_dimensionless(&self) -> bool {
+        self.exponents.iter().all(|exponent| *exponent == 0)
+    }
+
+    /// Raise every exponent, (m/s)^2 == m^2/s^2. An error when one no longer
+    /// fits, (m^70000)^70000
+    pub fn powi(self, power: i32) -> Result<Quantity, EvalError> {
+        let mut quantity = self;
+        for exponent in quantity.exponents.iter_mut() {
+            *exponent = exponent
+                .checked_mul(power)
+                .ok_or(EvalError::ExponentOverflow {
+                    span: Span::default(),
+                })?;
+        }
+        Ok(quantity)
+    }
+
+    /// Raise every exponent by a power that need not be an integer, as long as
+    /// the exponents it gives are integers that fit: (m^2)^0.5 is m, but m^0.5
+    /// and m^3000000000 are None
+    pub fn powf(self, power: f64) -> Option<Quantity> {
+        let mut quantity = self;
+        for exponent in quantity.exponents.iter_mut() {
+            let raised = *exponent as f64 * power;
+            let fits = (f64::from(i32::MIN)..=f64::from(i32::MAX)).contains(&raised.round());
+            if !fits || (raised - raised.round()).abs() > 1e-9 {
+                return None;
+            }
+            *exponent = raised.round() as i32;
+        }
+        Some(quantity)
+    }
+
+    /// The single base dimension this quantity is a power of, m^2 is Length
+    fn single_dimension(&self) -> Option<BaseDimension> {
+        let mut dimensions = BaseDimension::ALL
+            .into_iter()
+            .filter(|dimension| self.exponent(*dimension) != 0);
+        match (dimensions.next(), dimensions.next()) {
+            (Some(dimension), None) => Some(dimension),
+            _ => None,
+        }
+    }
+
+    /// The power lives in the quantity, so m^2 and m^3 are both Meter(1.0)
     fn get_base_unit(&self) -> UnitIdentity {
-        match self {
-            Quantity::None(power) => UnitIdentity::None(1.0),
-            Quantity::Time(power) => UnitIdentity::Second(1.0),
-            Quantity::Length(power) => match power {
-                1 => UnitIdentity::Meter(1.0),
-                2 => UnitIdentity::SquareMeter(1.0),
-                3 => UnitIdentity::CubicMeter(1.0),
-                _ => todo!("generic power"),
-            },
-            Quantity::Currency(power) => UnitIdentity::USD(1.0),
-            Quantity::CompoundQuantity {
-                operation,
-                lhs,
-                rhs,
-            } => UnitIdentity::CompoundUnit {
-                operation: operation.clone(),
-                lhs: Box::new(lhs.get_base_unit()),
-                rhs: Box::new(rhs.get_base_unit()),
-            },
+        if self.is_dimensionless() {
+            return UnitIdentity::None(1.0);
+        }
+        match self.single_dimension() {
+            Some(BaseDimension::Length) => UnitIdentity::Meter(1.0),
+            Some(BaseDimension::Mass) => UnitIdentity::Kilogram(1.0),
+            Some(BaseDimension::Time) => UnitIdentity::Second(1.0),
+            Some(BaseDimension::Temperature) => UnitIdentity::Kelvin(1.0),
+            Some(BaseDimension::AmountOfSubstance) => UnitIdentity::Mole(1.0),
+            Some(BaseDimension::ElectricCurrent) => UnitIdentity::Ampere(1.0),
+            Some(BaseDimension::LuminousIntensity) => UnitIdentity::Candela(1.0),
+            Some(BaseDimension::Currency) => UnitIdentity::USD(1.0),
+            None => UnitIdentity::Base(1.0),
+        }
+    }
+
+    /// Combine the exponents of both sides, an error when one does not fit
+    fn combine_exponents(
+        self,
+        rhs: Self,
+        combine: fn(i32, i32) -> Option<i32>,
+    ) -> Result<Self, EvalError> {
+        let mut quantity = self;
+        for (exponent, rhs_exponent) in quantity.exponents.iter_mut().zip(rhs.exponents) {
+            *exponent = combine(*exponent, rhs_exponent).ok_or(EvalError::ExponentOverflow {
+                span: Span::default(),
+            })?;
+        }
+        Ok(quantity)
+    }
+
+    /// Addition and subtraction keep the quantity, as long as both sides agree.
+    /// Dimensionless values are no exception, 1[m] + 1 is an error
+    fn combine_like(self, rhs: Self, operation: BinaryOperation) -> Result<Self, EvalError> {
+        if self == rhs {
+            return Ok(self);
         }
+        Err(EvalError::DimensionMismatch {
+            lhs: self,
+            rhs,
+            op: operation,
+            span: Span::default(),
+        })
     }
 }
 
 impl Add for Quantity {
-    type Output = Quantity;
+    type Output = Result<Quantity, EvalError>;
 
     /// we can only add when the units are identical
     /// and the addition returns the same unit:
     /// 1[m^1] + 1[m^1] == 2[m^1]
     /// 1[m^1] + 1[m^2] -> invalid!
-    fn add(self, rhs: Self) -> Self {
-        // if simple, use, otherwise simplify
-        let lhs_derived = match self {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                operation,
-                lhs,
-                rhs,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // lhs, rhs of compound lhs
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-
-        let rhs_derived = match rhs {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                lhs,
-                rhs,
-                operation,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // ?
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-
-        // if simple and type match, add powers
-        // otherwise compound
-        match lhs_derived {
-            Quantity::None(power) => rhs_derived,
-            Quantity::Time(_lhs_power) => match rhs_derived {
-                // if lhs_derived == rhs_derived
-                // note using lhs power in type comparison to check type and power at same time
-                Quantity::Time(lhs_power) => Quantity::Time(lhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => panic!("Cannot add {:#?} with {:#?}", lhs_derived, rhs_derived),
-            },
-            Quantity::Length(_lhs_power) => match rhs_derived {
-                Quantity::Length(lhs_power) => Quantity::Length(lhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => panic!("Cannot add {:#?} with {:#?}", lhs_derived, rhs_derived),
-            },
-            Quantity::Currency(_lhs_power) => match rhs_derived {
-                Quantity::Currency(lhs_power) => Quantity::Currency(lhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => panic!("Cannot add {:#?} with {:#?}", lhs_derived, rhs_derived),
-            },
-            _ => panic!("Cannot add {:#?} with {:#?}", lhs_derived, rhs_derived),
-        }
+    fn add(self, rhs: Self) -> Self::Output {
+        self.combine_like(rhs, BinaryOperation::Add)
     }
 }
 
 impl Sub for Quantity {
-    type Output = Quantity;
+    type Output = Result<Quantity, EvalError>;
 
     /// we can only subtract when the units are identical
     /// and the subtraction returns the same unit:
     /// 1[m^1] - 1[m^1] == 0[m^1]
     /// 1[m^1] - 1[m^2] -> invalid!
-    fn sub(self, rhs: Self) -> Self {
-        // if simple, use, otherwise simplify
-        let lhs_derived = match self {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                operation,
-                lhs,
-                rhs,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // lhs, rhs of compound lhs
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-
-        let rhs_derived = match rhs {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                lhs,
-                rhs,
-                operation,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // ?
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-
-        // if simple and type match, add powers
-        // otherwise compound
-        match lhs_derived {
-            Quantity::None(power) => rhs_derived,
-            Quantity::Time(_lhs_power) => match rhs_derived {
-                // if lhs_derived == rhs_derived
-                // note using lhs power in type comparison to check type and power at same time
-                Quantity::Time(lhs_power) => Quantity::Time(lhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => panic!("Cannot subtract {:#?} with {:#?}", lhs_derived, rhs_derived),
-            },
-            Quantity::Length(_lhs_power) => match rhs_derived {
-                Quantity::Length(lhs_power) => Quantity::Length(lhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => panic!("Cannot subtract {:#?} with {:#?}", lhs_derived, rhs_derived),
-            },
-            Quantity::Currency(_lhs_power) => match rhs_derived {
-                Quantity::Currency(lhs_power) => Quantity::Currency(lhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => panic!("Cannot subtract {:#?} with {:#?}", lhs_derived, rhs_derived),
-            },
-            _ => panic!("Cannot subtract {:#?} with {:#?}", lhs_derived, rhs_derived),
-        }
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.combine_like(rhs, BinaryOperation::Subtract)
     }
 }
 
 impl Mul for Quantity {
-    type Output = Quantity;
+    type Output = Result<Quantity, EvalError>;
 
     /// 1[m^1] * 1[m^2] = 1[m^3]
     /// a^1 * a^2 = a^3
-    /// multiplying a unit returns
-    /// like avaluate
-    ///
-    /// I think this will work
-    fn mul(self, rhs: Self) -> Self {
-        println!("Multiplying {:#?} with {:#?}", self.clone(), rhs.clone());
-        // if simple, use, otherwise simplify
-        let lhs_derived = match self {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                operation,
-                lhs,
-                rhs,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // lhs, rhs of compound lhs
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-
-        let rhs_derived = match rhs {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                lhs,
-                rhs,
-                operation,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // ?
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-        println!(
-            "Multiplying {:#?} with {:#?}",
-            lhs_derived.clone(),
-            rhs_derived.clone()
-        );
-
-        // if simple and type match, add powers
-        // otherwise compound
-        match lhs_derived {
-            Quantity::None(power) => rhs_derived,
-            Quantity::Time(lhs_power) => match rhs_derived {
-                Quantity::Time(rhs_power) => Quantity::Time(lhs_power + rhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Multiply,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-            Quantity::Length(lhs_power) => match rhs_derived {
-                Quantity::Length(rhs_power) => Quantity::Length(lhs_power + rhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Multiply,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-            Quantity::Currency(lhs_power) => match rhs_derived {
-                Quantity::Currency(rhs_power) => Quantity::Currency(lhs_power + rhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Multiply,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-            Quantity::CompoundQuantity { .. } => match rhs_derived {
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Multiply,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-        }
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.combine_exponents(rhs, i32::checked_add)
     }
 }
 
 impl Div for Quantity {
-    type Output = Quantity;
+    type Output = Result<Quantity, EvalError>;
 
-    /// 1[m^1] * 1[m^2] = 1[m^3]
-    /// a^1 * a^2 = a^3
-    fn div(self, rhs: Self) -> Self {
-        println!("Multiplying {:#?} with {:#?}", self.clone(), rhs.clone());
-        // if simple, use, otherwise simplify
-        let lhs_derived = match self {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                operation,
-                lhs,
-                rhs,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // lhs, rhs of compound lhs
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-
-        let rhs_derived = match rhs {
-            Quantity::None(power) => Quantity::None(power),
-            Quantity::Time(power) => Quantity::Time(power),
-            Quantity::Length(power) => Quantity::Length(power),
-            Quantity::Currency(power) => Quantity::Currency(power),
-            Quantity::CompoundQuantity {
-                lhs,
-                rhs,
-                operation,
-            } => match operation {
-                BinaryOperation::Add => *lhs + *rhs,
-                BinaryOperation::Subtract => *lhs - *rhs,
-                BinaryOperation::Multiply => *lhs * *rhs, // ?
-                BinaryOperation::Divide => *lhs / *rhs,
-            },
-        };
-        println!(
-            "Multiplying {:#?} with {:#?}",
-            lhs_derived.clone(),
-            rhs_derived.clone()
-        );
-
-        // if simple and type match, add powers
-        // otherwise compound
-        match lhs_derived {
-            Quantity::None(power) => rhs_derived,
-            Quantity::Time(lhs_power) => match rhs_derived {
-                Quantity::Time(rhs_power) => Quantity::Time(lhs_power - rhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Divide,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-            Quantity::Length(lhs_power) => match rhs_derived {
-                Quantity::Length(rhs_power) => Quantity::Length(lhs_power - rhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Divide,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-            Quantity::Currency(lhs_power) => match rhs_derived {
-                Quantity::Currency(rhs_power) => Quantity::Currency(lhs_power - rhs_power),
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Divide,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-            Quantity::CompoundQuantity { .. } => match rhs_derived {
-                Quantity::None(power) => lhs_derived,
-                _ => Quantity::CompoundQuantity {
-                    operation: BinaryOperation::Divide,
-                    lhs: Box::new(lhs_derived),
-                    rhs: Box::new(rhs_derived),
-                },
-            },
-        }
+    /// 1[m^3] / 1[m^1] = 1[m^2]
+    /// a^3 / a^1 = a^2
+    fn div(self, rhs: Self) -> Self::Output {
+        self.combine_exponents(rhs, i32::checked_sub)
     }
 }
 
@@ -401,78 +308,195 @@ pub struct Unit {
 }
 
 impl Unit {
-    fn get_conversion_factor(self) -> f64 {
-        match self.clone().unit {
+    /// The unit of a bare number, 2 or [1 2 3]
+    pub fn dimensionless() -> Unit {
+        Unit {
+            unit: UnitIdentity::None(1.0),
+            quantity: Quantity::dimensionless(),
+        }
+    }
+
+    /// Unit expressions keep their conversion factor, [km*h] is 1000 * 3600 m*s,
+    /// unlike arithmetic on values which converts to base units first. Symbols
+    /// that cancel are kept with a power of 0, so [m/m] is still written m/m
+    pub fn product(self, rhs: Unit) -> Result<Unit, EvalError> {
+        let factor = self.get_conversion_factor() * rhs.get_conversion_factor();
+        let quantity = (self.quantity * rhs.quantity)?;
+        let terms = match self.terms().zip(rhs.terms()) {
+            Some((mut terms, rhs_terms)) => {
+                for (symbol, power) in rhs_terms {
+                    match terms.iter_mut().find(|(existing, _)| *existing == symbol) {
+                        Some((_, existing_power)) => {
+                            *existing_power = existing_power.checked_add(power).ok_or(
+                                EvalError::ExponentOverflow {
+                                    span: Span::default(),
+                                },
+                            )?
+                        }
+                        None => terms.push((symbol, power)),
+                    }
+                }
+                Some(terms)
+            }
+            None => None,
+        };
+        Ok(Unit::from_terms(factor, quantity, terms))
+    }
+
+    /// [(km/h)^2] is (1000 / 3600)^2 m^2/s^2
+    pub fn powi(self, power: i32) -> Result<Unit, EvalError> {
+        let factor = self.get_conversion_factor().powi(power);
+        let quantity = self.quantity.powi(power)?;
+        let terms = match self.terms() {
+            Some(terms) => Some(
+                terms
+                    .into_iter()
+                    .map(|(symbol, exponent)| {
+                        exponent
+                            .checked_mul(power)
+                            .map(|exponent| (symbol, exponent))
+                            .ok_or(EvalError::ExponentOverflow {
+                                span: Span::default(),
+                            })
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        };
+        Ok(Unit::from_terms(factor, quantity, terms))
+    }
+
+    fn from_terms(factor: f64, quantity: Quantity, terms: Option<Vec<(String, i32)>>) -> Unit {
+        let unit = match terms {
+            Some(terms) if terms.is_empty() => UnitIdentity::None(factor),
+            Some(terms) => UnitIdentity::Compound(factor, terms),
+            None => UnitIdentity::Base(factor),
+        };
+        Unit { unit, quantity }
+    }
+
+    /// The unit symbols this unit is written with and their powers. None for
+    /// a multiple of base units that has no name
+    fn terms(&self) -> Option<Vec<(String, i32)>> {
+        match &self.unit {
+            UnitIdentity::Compound(_, terms) => Some(terms.clone()),
+            UnitIdentity::None(_) => Some(vec![]),
+            UnitIdentity::Base(factor) if *factor == 1.0 => Some(units::base_terms(&self.quantity)),
+            UnitIdentity::Base(_) => None,
+            _ => units::named_term(self).map(|term| vec![term]),
+        }
+    }
+
+    /// How the unit is written: km/h, m², J. `ascii` spells m² as m^2
+    pub fn symbol(&self, ascii: bool) -> String {
+        match &self.unit {
+            UnitIdentity::Base(1.0) => {
+                units::format_terms(&units::base_terms(&self.quantity), ascii)
+            }
+            UnitIdentity::Base(factor) => format!(
+                "({} {})",
+                factor,
+                Unit {
+                    unit: UnitIdentity::Base(1.0),
+                    quantity: self.quantity,
+                }
+                .symbol(ascii)
+            ),
+            _ => match self.terms() {
+                Some(terms) => units::format_terms(&terms, ascii),
+                None => Unit {
+                    unit: UnitIdentity::Base(self.get_conversion_factor()),
+                    quantity: self.quantity,
+                }
+                .symbol(ascii),
+            },
+        }
+    }
+
+    pub fn get_conversion_factor(&self) -> f64 {
+        match self.unit {
+            UnitIdentity::Base(factor) => factor,
+            UnitIdentity::Compound(factor, _) => factor,
             UnitIdentity::None(factor) => factor,
             UnitIdentity::Second(factor) => factor,
             UnitIdentity::Minute(factor) => factor,
             UnitIdentity::Hour(factor) => factor,
             UnitIdentity::Day(factor) => factor,
+            UnitIdentity::Week(factor) => factor,
+            UnitIdentity::Month(factor) => factor,
+            UnitIdentity::Year(factor) => factor,
             UnitIdentity::Meter(factor) => factor,
-            UnitIdentity::Kilometer(factor) => factor,
-            UnitIdentity::SquareMeter(factor) => factor,
-            UnitIdentity::SquareKilometer(factor) => factor,
-            UnitIdentity::CubicMeter(factor) => factor,
-            UnitIdentity::CubicKilometer(factor) => factor,
+            UnitIdentity::Newton(factor) => factor,
+            UnitIdentity::Joule(factor) => factor,
+            UnitIdentity::Watt(factor) => factor,
+            UnitIdentity::Pascal(factor) => factor,
+            UnitIdentity::Hertz(factor) => factor,
+            UnitIdentity::WattHour(factor) => factor,
+            UnitIdentity::Litre(factor) => factor,
+            UnitIdentity::Hectare(factor) => factor,
+            UnitIdentity::Gram(factor) => factor,
+            UnitIdentity::Kilogram(factor) => factor,
+            UnitIdentity::Kelvin(factor) => factor,
+            UnitIdentity::Mole(factor) => factor,
+            UnitIdentity::Ampere(factor) => factor,
+            UnitIdentity::Candela(factor) => factor,
             UnitIdentity::USD(factor) => factor,
             UnitIdentity::GBP(factor) => factor,
-            UnitIdentity::CompoundUnit { .. } => 1.0,
         }
     }
 }
 
 impl Add for Unit {
-    type Output = Unit;
+    type Output = Result<Unit, EvalError>;
 
-    fn add(self, rhs: Self) -> Self {
-        let derived_quantity = self.quantity + rhs.quantity;
+    fn add(self, rhs: Self) -> Self::Output {
+        let derived_quantity = (self.quantity + rhs.quantity)?;
         let unit = derived_quantity.get_base_unit();
-        Unit {
-            unit: unit,
+        Ok(Unit {
+            unit,
             quantity: derived_quantity,
-        }
+        })
     }
 }
 
 impl Sub for Unit {
-    type Output = Unit;
+    type Output = Result<Unit, EvalError>;
 
-    fn sub(self, rhs: Self) -> Self {
-        let derived_quantity = self.quantity - rhs.quantity;
+    fn sub(self, rhs: Self) -> Self::Output {
+        let derived_quantity = (self.quantity - rhs.quantity)?;
         let unit = derived_quantity.get_base_unit();
-        Unit {
-            unit: unit,
+        Ok(Unit {
+            unit,
             quantity: derived_quantity,
-        }
+        })
     }
 }
 
 impl Mul for Unit {
-    type Output = Unit;
+    type Output = Result<Unit, EvalError>;
 
     /// 1[m^1] * 1[m^2] = 1[m^3]
     /// a^1 * a^2 = a^3
-    fn mul(self, rhs: Self) -> Self {
-        let derived_quantity = self.quantity * rhs.quantity;
+    fn mul(self, rhs: Self) -> Self::Output {
+        let derived_quantity = (self.quantity * rhs.quantity)?;
         let unit = derived_quantity.get_base_unit();
-        Unit {
-            unit: unit,
+        Ok(Unit {
+            unit,
             quantity: derived_quantity,
-        }
+        })
     }
 }
 
 impl Div for Unit {
-    type Output = Unit;
+    type Output = Result<Unit, EvalError>;
 
-    fn div(self, rhs: Self) -> Self {
-        let derived_quantity = self.quantity / rhs.quantity;
-        println!("Div for Unit: derived_quantity = {:#?}", derived_quantity);
+    fn div(self, rhs: Self) -> Self::Output {
+        let derived_quantity = (self.quantity / rhs.quantity)?;
         let unit = derived_quantity.get_base_unit();
-        Unit {
-            unit: unit,
+        Ok(Unit {
+            unit,
             quantity: derived_quantity,
-        }
+        })
     }
 }
 
@@ -486,7 +510,13 @@ fn scalar_vector_addition(scalar: f64, vector: Vec<f64>) -> Vec<f64> {
     vector.iter().map(|left_x| left_x + scalar).collect()
 }
 
+/// scalar - [x y] == [scalar - x, scalar - y]
 fn scalar_vector_subtraction(scalar: f64, vector: Vec<f64>) -> Vec<f64> {
+    vector.iter().map(|right_x| scalar - right_x).collect()
+}
+
+/// [x y] - scalar == [x - scalar, y - scalar]
+fn vector_scalar_subtraction(vector: Vec<f64>, scalar: f64) -> Vec<f64> {
     vector.iter().map(|left_x| left_x - scalar).collect()
 }
 
@@ -494,47 +524,168 @@ fn scalar_vector_multiplication(scalar: f64, vector: Vec<f64>) -> Vec<f64> {
     vector.iter().map(|left_x| left_x * scalar).collect()
 }
 
+/// scalar / [x y] == [scalar / x, scalar / y]
 fn scalar_vector_division(scalar: f64, vector: Vec<f64>) -> Vec<f64> {
+    vector.iter().map(|right_x| scalar / right_x).collect()
+}
+
+/// [x y] / scalar == [x / scalar, y / scalar]
+fn vector_scalar_division(vector: Vec<f64>, scalar: f64) -> Vec<f64> {
     vector.iter().map(|left_x| left_x / scalar).collect()
 }
 
-fn elementwise_vector_addition(lhs_value: Vec<f64>, rhs_value: Vec<f64>) -> Vec<f64> {
-    lhs_value
-        .iter()
-        .zip(rhs_value)
-        .map(|(left_x, right_x)| left_x + right_x)
-        .collect()
+/// Combine two vectors element by element. The broadcasting rules are:
+/// - equal lengths pair up elements: [1 2 3] + [10 20 30] == [11 22 33]
+/// - a single element vector is extended to the length of the other, like a
+///   scalar: [1] + [10 20 30] == [11 21 31]
+/// - any other pair of lengths is an error, zip would silently drop the extra
+///   elements of the longer one
+fn elementwise(
+    lhs_value: Vec<f64>,
+    rhs_value: Vec<f64>,
+    operation: fn(f64, f64) -> f64,
+) -> Result<Vec<f64>, EvalError> {
+    match (lhs_value.len(), rhs_value.len()) {
+        (lhs_length, rhs_length) if lhs_length == rhs_length => Ok(lhs_value
+            .into_iter()
+            .zip(rhs_value)
+            .map(|(left_x, right_x)| operation(left_x, right_x))
+            .collect()),
+        (1, _) => Ok(rhs_value
+            .into_iter()
+            .map(|right_x| operation(lhs_value[0], right_x))
+            .collect()),
+        (_, 1) => Ok(lhs_value
+            .into_iter()
+            .map(|left_x| operation(left_x, rhs_value[0]))
+            .collect()),
+        (lhs_length, rhs_length) => Err(EvalError::VectorLengthMismatch {
+            lhs: lhs_length,
+            rhs: rhs_length,
+            span: Span::default(),
+        }),
+    }
 }
 
-fn elementwise_vector_subtraction(lhs_value: Vec<f64>, rhs_value: Vec<f64>) -> Vec<f64> {
-    lhs_value
-        .iter()
-        .zip(rhs_value)
-        .map(|(left_x, right_x)| left_x - right_x)
-        .collect()
+fn elementwise_vector_addition(
+    lhs_value: Vec<f64>,
+    rhs_value: Vec<f64>,
+) -> Result<Vec<f64>, EvalError> {
+    elementwise(lhs_value, rhs_value, |left_x, right_x| left_x + right_x)
 }
 
-fn elementwise_vector_multiplication(lhs_value: Vec<f64>, rhs_value: Vec<f64>) -> Vec<f64> {
-    lhs_value
-        .iter()
-        .zip(rhs_value)
-        .map(|(left_x, right_x)| left_x * right_x)
-        .collect()
+fn elementwise_vector_subtraction(
+    lhs_value: Vec<f64>,
+    rhs_value: Vec<f64>,
+) -> Result<Vec<f64>, EvalError> {
+    elementwise(lhs_value, rhs_value, |left_x, right_x| left_x - right_x)
 }
 
-fn elementwise_vector_division(lhs_value: Vec<f64>, rhs_value: Vec<f64>) -> Vec<f64> {
-    lhs_value
-        .iter()
-        .zip(rhs_value)
-        .map(|(left_x, right_x)| left_x / right_x)
-        .collect()
+fn elementwise_vector_multiplication(
+    lhs_value: Vec<f64>,
+    rhs_value: Vec<f64>,
+) -> Result<Vec<f64>, EvalError> {
+    elementwise(lhs_value, rhs_value, |left_x, right_x| left_x * right_x)
 }
 
-impl Add for Value {
-    type Output = Value;
+fn elementwise_vector_division(
+    lhs_value: Vec<f64>,
+    rhs_value: Vec<f64>,
+) -> Result<Vec<f64>, EvalError> {
+    elementwise(lhs_value, rhs_value, |left_x, right_x| left_x / right_x)
+}
 
-    fn add(self, rhs: Self) -> Self {
+impl Value {
+    /// Multiply by a conversion factor, which can never fail
+    pub fn scale(self, factor: f64) -> Value {
         match self {
+            Value::Float(value) => Value::Float(value * factor),
+            Value::Vec(values) => Value::Vec(scalar_vector_multiplication(factor, values)),
+        }
+    }
+
+    /// Raise to `exponent`, broadcasting like the arithmetic operators. Zero
+    /// to a negative power is a division by zero, as 0^-1 is 1 / 0
+    pub fn pow(self, exponent: Value) -> Result<Value, EvalError> {
+        let zero_to_negative = |base: f64, power: f64| base == 0.0 && power < 0.0;
+        let divides_by_zero = match (&self, &exponent) {
+            (Value::Float(base), Value::Float(power)) => zero_to_negative(*base, *power),
+            (Value::Vec(bases), Value::Float(power)) => {
+                bases.iter().any(|base| zero_to_negative(*base, *power))
+            }
+            (Value::Float(base), Value::Vec(powers)) => {
+                powers.iter().any(|power| zero_to_negative(*base, *power))
+            }
+            (Value::Vec(bases), Value::Vec(powers)) => {
+                match (bases.as_slice(), powers.as_slice()) {
+                    ([base], powers) => powers.iter().any(|power| zero_to_negative(*base, *power)),
+                    (bases, [power]) => bases.iter().any(|base| zero_to_negative(*base, *power)),
+                    (bases, powers) => bases
+                        .iter()
+                        .zip(powers)
+                        .any(|(base, power)| zero_to_negative(*base, *power)),
+                }
+            }
+        };
+        if divides_by_zero {
+            return Err(EvalError::DivisionByZero {
+                span: Span::default(),
+            });
+        }
+
+        Ok(match (self, exponent) {
+            (Value::Float(base), Value::Float(power)) => Value::Float(base.powf(power)),
+            (Value::Vec(bases), Value::Float(power)) => {
+                Value::Vec(bases.iter().map(|base| base.powf(power)).collect())
+            }
+            (Value::Float(base), Value::Vec(powers)) => {
+                Value::Vec(powers.iter().map(|power| base.powf(*power)).collect())
+            }
+            (Value::Vec(bases), Value::Vec(powers)) => {
+                Value::Vec(elementwise(bases, powers, f64::powf)?)
+            }
+        })
+    }
+
+    /// The value rounded to `significant_figures`, vectors as [1, 2, 3]
+    pub fn format(&self, significant_figures: usize) -> String {
+        match self {
+            Value::Float(value) => format_number(*value, significant_figures),
+            Value::Vec(values) => format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|value| format_number(*value, significant_figures))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// The one power every element is raised by, if there is one
+    fn single_power(&self) -> Option<f64> {
+        match self {
+            Value::Float(power) => Some(*power),
+            Value::Vec(powers) => match powers.split_first() {
+                Some((first, rest)) if rest.iter().all(|power| power == first) => Some(*first),
+                _ => None,
+            },
+        }
+    }
+
+    fn contains_zero(&self) -> bool {
+        match self {
+            Value::Float(value) => *value == 0.0,
+            Value::Vec(values) => values.contains(&0.0),
+        }
+    }
+}
+
+impl Add for Value {
+    type Output = Result<Value, EvalError>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Ok(match self {
             // we are float
             Value::Float(lhs_value) => match rhs {
                 // they are float
@@ -548,18 +699,18 @@ impl Add for Value {
                 Value::Float(rhs_value) => Value::Vec(scalar_vector_addition(rhs_value, lhs_value)),
                 // they are vec
                 Value::Vec(rhs_value) => {
-                    Value::Vec(elementwise_vector_addition(lhs_value, rhs_value))
+                    Value::Vec(elementwise_vector_addition(lhs_value, rhs_value)?)
                 }
             },
-        }
+        })
     }
 }
 
 impl Sub for Value {
-    type Output = Value;
+    type Output = Result<Value, EvalError>;
 
-    fn sub(self, rhs: Self) -> Self {
-        match self {
+    fn sub(self, rhs: Self) -> Self::Output {
+        Ok(match self {
             // we are float
             Value::Float(lhs_value) => match rhs {
                 // they are float
@@ -573,22 +724,22 @@ impl Sub for Value {
             Value::Vec(lhs_value) => match rhs {
                 // they are float
                 Value::Float(rhs_value) => {
-                    Value::Vec(scalar_vector_subtraction(rhs_value, lhs_value))
+                    Value::Vec(vector_scalar_subtraction(lhs_value, rhs_value))
                 }
                 // they are vec
                 Value::Vec(rhs_value) => {
-                    Value::Vec(elementwise_vector_subtraction(lhs_value, rhs_value))
+                    Value::Vec(elementwise_vector_subtraction(lhs_value, rhs_value)?)
                 }
             },
-        }
+        })
     }
 }
 
 impl Mul for Value {
-    type Output = Value;
+    type Output = Result<Value, EvalError>;
 
-    fn mul(self, rhs: Self) -> Self {
-        match self {
+    fn mul(self, rhs: Self) -> Self::Output {
+        Ok(match self {
             // we are float
             Value::Float(lhs_value) => match rhs {
                 // they are float
@@ -606,18 +757,24 @@ impl Mul for Value {
                 }
                 // they are vec
                 Value::Vec(rhs_value) => {
-                    Value::Vec(elementwise_vector_multiplication(lhs_value, rhs_value))
+                    Value::Vec(elementwise_vector_multiplication(lhs_value, rhs_value)?)
                 }
             },
-        }
+        })
     }
 }
 
 impl Div for Value {
-    type Output = Value;
+    type Output = Result<Value, EvalError>;
 
-    fn div(self, rhs: Self) -> Self {
-        match self {
+    fn div(self, rhs: Self) -> Self::Output {
+        if rhs.contains_zero() {
+            return Err(EvalError::DivisionByZero {
+                span: Span::default(),
+            });
+        }
+
+        Ok(match self {
             // we are float
             Value::Float(lhs_value) => match rhs {
                 // they are float
@@ -628,13 +785,68 @@ impl Div for Value {
             // we are vec
             Value::Vec(lhs_value) => match rhs {
                 // they are float
-                Value::Float(rhs_value) => Value::Vec(scalar_vector_division(rhs_value, lhs_value)),
+                Value::Float(rhs_value) => Value::Vec(vector_scalar_division(lhs_value, rhs_value)),
                 // they are vec
                 Value::Vec(rhs_value) => {
-                    Value::Vec(elementwise_vector_division(lhs_value, rhs_value))
+                    Value::Vec(elementwise_vector_division(lhs_value, rhs_value)?)
                 }
             },
-        }
+        })
+    }
+}
+
+/// Significant figures numbers are printed with unless a precision is given,
+/// `format!("{:.3}", value)`
+pub const DEFAULT_SIGNIFICANT_FIGURES: usize = 10;
+
+/// `value` rounded to `significant_figures`, without trailing zeros. Very
+/// large and very small numbers are written in scientific notation, 1.5e-7
+fn format_number(value: f64, significant_figures: usize) -> String {
+    if value == 0.0 || !value.is_finite() {
+        return (value + 0.0).to_string();
+    }
+    let significant_figures = significant_figures.max(1);
+    let exponent = value.abs().log10().floor() as i32;
+
+    if !(-4..15).contains(&exponent) {
+        let formatted = format!("{:.*e}", significant_figures - 1, value);
+        let (mantissa, exponent) = formatted.split_once('e').unwrap();
+        return format!("{}e{}", trim_zeros(mantissa), exponent);
+    }
+
+    let decimals = (significant_figures as i32 - 1 - exponent).max(0) as usize;
+    let rounded = if decimals == 0 {
+        let scale = 10f64.powi(exponent + 1 - significant_figures as i32);
+        (value / scale).round() * scale
+    } else {
+        value
+    };
+    trim_zeros(&format!("{:.*}", decimals, rounded)).to_string()
+}
+
+fn trim_zeros(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+/// `{}` prints 10 significant figures, `{:.3}` prints 3
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.format(f.precision().unwrap_or(DEFAULT_SIGNIFICANT_FIGURES))
+        )
+    }
+}
+
+/// `{}` prints km·m², `{:#}` prints km*m^2
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.symbol(f.alternate()))
     }
 }
 
@@ -645,111 +857,216 @@ pub struct DimensionedValue {
 }
 
 impl Add for DimensionedValue {
-    type Output = DimensionedValue;
-
-    fn add(self, rhs: Self) -> Self {
-        println!("\n\nAdding {:#?} to {:#?}", self, rhs);
+    type Output = Result<DimensionedValue, EvalError>;
 
+    fn add(self, rhs: Self) -> Self::Output {
         // the conversion to base units MUST happen here
         // at the outmost scope, so we dont have to write match arms for every
         // downstream Mul, Add, etc
-        let lhs_value_in_base_units =
-            self.value * Value::Float(self.unit.clone().get_conversion_factor());
-        let rhs_value_in_base_units =
-            rhs.value * Value::Float(rhs.unit.clone().get_conversion_factor());
+        let lhs_value_in_base_units = self.value.scale(self.unit.get_conversion_factor());
+        let rhs_value_in_base_units = rhs.value.scale(rhs.unit.get_conversion_factor());
 
-        let unit = self.unit + rhs.unit;
-        let value = lhs_value_in_base_units + rhs_value_in_base_units;
+        let unit = (self.unit + rhs.unit)?;
+        let value = (lhs_value_in_base_units + rhs_value_in_base_units)?;
 
-        println!("\nResult = {:#?}", value);
-
-        DimensionedValue {
-            value: value,
-            unit: unit,
-        }
+        Ok(DimensionedValue { value, unit })
     }
 }
 
 impl Sub for DimensionedValue {
-    type Output = DimensionedValue;
-
-    fn sub(self, rhs: Self) -> Self {
-        println!("\n\nSubtracting {:#?} from {:#?}", rhs, self);
+    type Output = Result<DimensionedValue, EvalError>;
 
+    fn sub(self, rhs: Self) -> Self::Output {
         // the conversion to base units MUST happen here
         // at the outmost scope, so we dont have to write match arms for every
         // downstream Mul, Add, etc
-        let lhs_value_in_base_units =
-            self.value * Value::Float(self.unit.clone().get_conversion_factor());
-        let rhs_value_in_base_units =
-            rhs.value * Value::Float(rhs.unit.clone().get_conversion_factor());
-
-        let unit = self.unit - rhs.unit;
-        let value = lhs_value_in_base_units - rhs_value_in_base_units;
+        let lhs_value_in_base_units = self.value.scale(self.unit.get_conversion_factor());
+        let rhs_value_in_base_units = rhs.value.scale(rhs.unit.get_conversion_factor());
 
-        println!("\nResult = {:#?}", value);
+        let unit = (self.unit - rhs.unit)?;
+        let value = (lhs_value_in_base_units - rhs_value_in_base_units)?;
 
-        DimensionedValue {
-            value: value,
-            unit: unit,
-        }
+        Ok(DimensionedValue { value, unit })
     }
 }
 
 impl Mul for DimensionedValue {
-    type Output = DimensionedValue;
-
-    fn mul(self, rhs: Self) -> Self {
-        println!("\n\nMultiplying {:#?} with {:#?}", self, rhs);
+    type Output = Result<DimensionedValue, EvalError>;
 
+    fn mul(self, rhs: Self) -> Self::Output {
         // the conversion to base units MUST happen here
         // at the outmost scope, so we dont have to write match arms for every
         // downstream Mul, Add, etc
-        let lhs_value_in_base_units =
-            self.value * Value::Float(self.unit.clone().get_conversion_factor());
-        let rhs_value_in_base_units =
-            rhs.value * Value::Float(rhs.unit.clone().get_conversion_factor());
+        let lhs_value_in_base_units = self.value.scale(self.unit.get_conversion_factor());
+        let rhs_value_in_base_units = rhs.value.scale(rhs.unit.get_conversion_factor());
 
-        let unit = self.unit * rhs.unit;
-        let value = lhs_value_in_base_units * rhs_value_in_base_units;
+        let unit = (self.unit * rhs.unit)?;
+        let value = (lhs_value_in_base_units * rhs_value_in_base_units)?;
 
-        println!("\nResult = {:#?}", value);
-
-        DimensionedValue {
-            value: value,
-            unit: unit,
-        }
+        Ok(DimensionedValue { value, unit })
     }
 }
 
 impl Div for DimensionedValue {
-    type Output = DimensionedValue;
-
-    fn div(self, rhs: Self) -> Self {
-        println!("\n\nDividing {:#?} into {:#?}", self, rhs);
+    type Output = Result<DimensionedValue, EvalError>;
 
+    fn div(self, rhs: Self) -> Self::Output {
         // the conversion to base units MUST happen here
         // at the outmost scope, so we dont have to write match arms for every
         // downstream Mul, Add, etc
-        let lhs_value_in_base_units =
-            self.value * Value::Float(self.unit.clone().get_conversion_factor());
-        let rhs_value_in_base_units =
-            rhs.value * Value::Float(rhs.unit.clone().get_conversion_factor());
-
-        println!(
-            "\n\nLHS in base units {:#?} / RHS in base units {:#?}",
-            lhs_value_in_base_units.clone(),
-            rhs_value_in_base_units.clone()
-        );
+        let lhs_value_in_base_units = self.value.scale(self.unit.get_conversion_factor());
+        let rhs_value_in_base_units = rhs.value.scale(rhs.unit.get_conversion_factor());
 
-        let unit = self.unit / rhs.unit;
-        let value = lhs_value_in_base_units / rhs_value_in_base_units;
+        let unit = (self.unit / rhs.unit)?;
+        let value = (lhs_value_in_base_units / rhs_value_in_base_units)?;
 
-        println!("\nResult = {:#?}", value);
+        Ok(DimensionedValue { value, unit })
+    }
+}
 
-        DimensionedValue {
-            value: value,
-            unit: unit,
+/// The value in its unit, 1.23 km/h or [1, 2, 3] m. The precision is the
+/// number of significant figures and `{:#}` writes units in ASCII, km/h^2
+impl fmt::Display for DimensionedValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let DimensionedValue { value, unit } = self.displayed();
+        let value = value.format(f.precision().unwrap_or(DEFAULT_SIGNIFICANT_FIGURES));
+        match unit.symbol(f.alternate()) {
+            symbol if symbol.is_empty() => write!(f, "{}", value),
+            symbol => write!(f, "{} {}", value, symbol),
+        }
+    }
+}
+
+impl DimensionedValue {
+    /// The value in the unit it is printed in. An unnamed multiple of base
+    /// units has no symbol to print with it, so it is converted to them
+    pub fn displayed(&self) -> DimensionedValue {
+        match self.unit.unit {
+            UnitIdentity::Base(factor) if factor != 1.0 => DimensionedValue {
+                value: self.value.clone().scale(factor),
+                unit: Unit {
+                    unit: UnitIdentity::Base(1.0),
+                    quantity: self.unit.quantity,
+                },
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// The same value in the derived unit of its quantity, kg·m²/s² as J and
+    /// 1/s as Hz, whether it was written that way or computed. A value already
+    /// in a derived unit, 2[kWh] or 3[L], keeps it
+    pub fn in_derived_units(&self) -> DimensionedValue {
+        let already_derived = units::registry().iter().any(|definition| {
+            definition.derived
+                && std::mem::discriminant(&(definition.identity)(1.0))
+                    == std::mem::discriminant(&self.unit.unit)
+        });
+        match units::derived_unit_for(&self.unit.quantity) {
+            Some(unit) if !already_derived => DimensionedValue {
+                value: self.value.clone().scale(self.unit.get_conversion_factor()),
+                unit,
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// The same value expressed in `unit`, which is kept as its display unit:
+    /// 7200[s] in [h] is 2[h]. The quantities have to match
+    pub fn convert_to(self, unit: Unit) -> Result<DimensionedValue, EvalError> {
+        if self.unit.quantity != unit.quantity {
+            return Err(EvalError::ConversionMismatch {
+                from: self.unit.quantity,
+                to: unit.quantity,
+                span: Span::default(),
+            });
+        }
+
+        let value_in_base_units = self.value.scale(self.unit.get_conversion_factor());
+        let value = (value_in_base_units / Value::Float(unit.get_conversion_factor()))?;
+
+        Ok(DimensionedValue { value, unit })
+    }
+
+    /// Raise to a dimensionless `exponent`. Every exponent of the unit is
+    /// raised too, so (2[m])^3 is 8 m^3, and a fractional power is fine as
+    /// long as the unit it gives is whole: (4[m^2])^0.5 is 2 m, 2[m]^0.5 is an
+    /// error. A dimensioned vector can only be raised by one power, otherwise
+    /// each element would end up with a different unit
+    pub fn pow(self, exponent: DimensionedValue) -> Result<DimensionedValue, EvalError> {
+        if !exponent.unit.quantity.is_dimensionless() {
+            return Err(EvalError::DimensionedExponent {
+                exponent: exponent.unit.quantity,
+                span: Span::default(),
+            });
+        }
+
+        let base_in_base_units = self.value.scale(self.unit.get_conversion_factor());
+        let exponent_in_base_units = exponent.value.scale(exponent.unit.get_conversion_factor());
+
+        let quantity = if self.unit.quantity.is_dimensionless() {
+            Quantity::dimensionless()
+        } else {
+            exponent_in_base_units
+                .single_power()
+                .and_then(|power| self.unit.quantity.powf(power))
+                .ok_or_else(|| EvalError::InvalidPower {
+                    base: self.unit.quantity,
+                    power: exponent_in_base_units.clone(),
+                    span: Span::default(),
+                })?
+        };
+
+        Ok(DimensionedValue {
+            value: base_in_base_units.pow(exponent_in_base_units)?,
+            unit: Unit {
+                unit: quantity.get_base_unit(),
+                quantity,
+            },
+        })
+    }
+}
+
+/// Where a node starts, as the number of bytes from there to the end of the
+/// source, which the parser always knows
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub from_end: usize,
+}
+
+/// The declared unit of a variable, `wage[USD/month]`, or its dimension,
+/// `wage[currency/time]`. Either way the value has to have its quantity
+#[derive(PartialEq, Debug, Clone)]
+pub enum Annotation {
+    Unit(Unit),
+    Dimension(Quantity),
+}
+
+impl Annotation {
+    pub fn quantity(&self) -> Quantity {
+        match self {
+            Annotation::Unit(unit) => unit.quantity,
+            Annotation::Dimension(quantity) => *quantity,
+        }
+    }
+}
+
+/// As written in source, [km/h] or [currency/time]
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Annotation::Unit(unit) => write!(f, "[{}]", source_symbol(unit)),
+            Annotation::Dimension(quantity) if quantity.is_dimensionless() => {
+                write!(f, "[dimensionless]")
+            }
+            Annotation::Dimension(quantity) => {
+                let terms: Vec<(String, i32)> = BaseDimension::ALL
+                    .iter()
+                    .filter(|dimension| quantity.exponent(**dimension) != 0)
+                    .map(|dimension| (dimension.name().to_string(), quantity.exponent(*dimension)))
+                    .collect();
+                write!(f, "[{}]", units::format_terms(&terms, true))
+            }
         }
     }
 }
@@ -765,14 +1082,751 @@ pub enum AstNode {
         value: Value,
         unit: Unit,
     },
-    Name(String),
+    Name(String, Span),
     Expression {
         operation: BinaryOperation,
         lhs: Box<AstNode>,
         rhs: Box<AstNode>,
+        span: Span,
     },
     Variable {
         name: Box<AstNode>,
+        annotation: Option<Annotation>,
+        expr: Box<AstNode>,
+        /// the `##` comments before the variable, without the `##`
+        doc: Option<String>,
+    },
+    /// `expr as [unit]`, the span is at `as`
+    Conversion {
         expr: Box<AstNode>,
+        unit: Unit,
+        span: Span,
+    },
+    /// `csv{column[unit]}`, a column of the csv files the program is run with
+    Csv {
+        column: String,
+        unit: Unit,
+        span: Span,
     },
 }
+
+/// A unit as it is written in brackets in source. A plain number has no
+/// symbols, so it is written `none`
+fn source_symbol(unit: &Unit) -> String {
+    match unit.symbol(true) {
+        symbol if symbol.is_empty() => "none".to_string(),
+        symbol => symbol,
+    }
+}
+
+/// A number or vector as a literal, 2[km], [1 2 3][m] or 15
+fn write_literal(f: &mut fmt::Formatter, value: &Value, unit: &Unit) -> fmt::Result {
+    match value {
+        Value::Float(value) => write!(f, "{}", value)?,
+        Value::Vec(values) => write!(
+            f,
+            "[{}]",
+            values
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        )?,
+    }
+    match unit.symbol(true) {
+        symbol if symbol.is_empty() => Ok(()),
+        symbol => write!(f, "[{}]", symbol),
+    }
+}
+
+impl AstNode {
+    /// What `-x` negates, it is parsed as `-1 * x`. A negative literal is
+    /// parsed as one, so `-1 * 2[m]` is not a negation
+    fn negated(&self) -> Option<&AstNode> {
+        match self {
+            AstNode::Expression {
+                operation: BinaryOperation::Multiply,
+                lhs,
+                rhs,
+                ..
+            } if matches!(
+                lhs.as_ref(),
+                AstNode::Double {
+                    value: Value::Float(value),
+                    unit,
+                } if *value == -1.0 && *unit == Unit::dimensionless()
+            ) && !matches!(
+                rhs.as_ref(),
+                AstNode::Double { .. } | AstNode::Vector { .. }
+            ) =>
+            {
+                Some(rhs)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl AstNode {
+    /// The node with every span reset, for comparing trees parsed from
+    /// differently laid out source
+    pub fn without_spans(self) -> AstNode {
+        let boxed = |node: Box<AstNode>| Box::new(node.without_spans());
+        match self {
+            AstNode::Print(node) => AstNode::Print(boxed(node)),
+            AstNode::Name(name, _) => AstNode::Name(name, Span::default()),
+            AstNode::Expression {
+                operation,
+                lhs,
+                rhs,
+                ..
+            } => AstNode::Expression {
+                operation,
+                lhs: boxed(lhs),
+                rhs: boxed(rhs),
+                span: Span::default(),
+            },
+            AstNode::Variable {
+                name,
+                annotation,
+                expr,
+                doc,
+            } => AstNode::Variable {
+                name: boxed(name),
+                annotation,
+                expr: boxed(expr),
+                doc,
+            },
+            AstNode::Conversion { expr, unit, .. } => AstNode::Conversion {
+                expr: boxed(expr),
+                unit,
+                span: Span::default(),
+            },
+            AstNode::Csv { column, unit, .. } => AstNode::Csv {
+                column,
+                unit,
+                span: Span::default(),
+            },
+            node @ (AstNode::Double { .. } | AstNode::Vector { .. }) => node,
+        }
+    }
+}
+
+/// The node as source that parses back to it, with only the parentheses
+/// precedence needs: 2[km] / (a + b) as [h]
+impl fmt::Display for AstNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AstNode::Print(node) => write!(f, "{}", node),
+            AstNode::Double { value, unit } | AstNode::Vector { value, unit } => {
+                write_literal(f, value, unit)
+            }
+            AstNode::Name(name, _) => write!(f, "{}", name),
+            AstNode::Expression {
+                operation,
+                lhs,
+                rhs,
+                ..
+            } => {
+                if let Some(operand) = self.negated() {
+                    return match operand {
+                        AstNode::Expression {
+                            operation: BinaryOperation::Power,
+                            ..
+                        } => write!(f, "-{}", operand),
+                        AstNode::Expression { .. } if operand.negated().is_none() => {
+                            write!(f, "-({})", operand)
+                        }
+                        AstNode::Conversion { .. } => write!(f, "-({})", operand),
+                        operand => write!(f, "-{}", operand),
+                    };
+                }
+                // operators group to the left except `^`, which groups right.
+                // A negation is an operand itself, only `^` binds tighter, and
+                // so does a negative literal, as -2^2 is -(2^2)
+                let needs_parentheses = |operand: &AstNode, is_lhs: bool| match operand {
+                    _ if operand.negated().is_some() => {
+                        is_lhs && *operation == BinaryOperation::Power
+                    }
+                    AstNode::Double {
+                        value: Value::Float(value),
+                        ..
+                    } => is_lhs && *operation == BinaryOperation::Power && value.is_sign_negative(),
+                    AstNode::Expression {
+                        operation: inner, ..
+                    } => {
+                        inner.precedence() < operation.precedence()
+                            || (inner.precedence() == operation.precedence()
+                                && is_lhs == (*operation == BinaryOperation::Power))
+                    }
+                    AstNode::Conversion { .. } => true,
+                    _ => false,
+                };
+                let operand = |operand: &AstNode, is_lhs: bool| {
+                    if needs_parentheses(operand, is_lhs) {
+                        format!("({})", operand)
+                    } else {
+                        operand.to_string()
+                    }
+                };
+                match operation {
+                    BinaryOperation::Power => {
+                        write!(f, "{}^{}", operand(lhs, true), operand(rhs, false))
+                    }
+                    operation => write!(
+                        f,
+                        "{} {} {}",
+                        operand(lhs, true),
+                        operation.symbol(),
+                        operand(rhs, false)
+                    ),
+                }
+            }
+            AstNode::Variable {
+                name,
+                annotation,
+                expr,
+                doc,
+            } => {
+                for line in doc.iter().flat_map(|doc| doc.lines()) {
+                    match line {
+                        "" => writeln!(f, "##")?,
+                        line => writeln!(f, "## {}", line)?,
+                    }
+                }
+                write!(f, "{}", name)?;
+                if let Some(annotation) = annotation {
+                    write!(f, "{}", annotation)?;
+                }
+                write!(f, " = {};", expr)
+            }
+            AstNode::Conversion { expr, unit, .. } => {
+                write!(f, "{} as [{}]", expr, source_symbol(unit))
+            }
+            AstNode::Csv { column, unit, .. } => match unit.symbol(true) {
+                symbol if symbol.is_empty() => write!(f, "csv{{{}}}", column),
+                symbol => write!(f, "csv{{{}[{}]}}", column, symbol),
+            },
+        }
+    }
+}
+
+#[test]
+fn test_quantity_cancels() {
+    let velocity = (Quantity::base(BaseDimension::Length, 1)
+        / Quantity::base(BaseDimension::Time, 1))
+    .unwrap();
+    assert_eq!(
+        velocity * Quantity::base(BaseDimension::Time, 1),
+        Ok(Quantity::base(BaseDimension::Length, 1))
+    );
+    assert_eq!(velocity.exponent(BaseDimension::Time), -1);
+    assert!((velocity / velocity).unwrap().is_dimensionless());
+
+    // exponents that no longer fit are an error, not a panic
+    let huge = Quantity::base(BaseDimension::Length, 2_000_000_000);
+    assert!(matches!(
+        huge * huge,
+        Err(EvalError::ExponentOverflow { .. })
+    ));
+    assert!(matches!(
+        Quantity::base(BaseDimension::Length, -2_000_000_000) / huge,
+        Err(EvalError::ExponentOverflow { .. })
+    ));
+    assert!(matches!(
+        huge.powi(2),
+        Err(EvalError::ExponentOverflow { .. })
+    ));
+}
+
+#[test]
+fn test_add_compound_quantities() {
+    let kilometers_per_day = DimensionedValue {
+        value: Value::Float(86.4),
+        unit: Unit {
+            unit: UnitIdentity::Meter(1000.0),
+            quantity: Quantity::base(BaseDimension::Length, 1),
+        },
+    } / DimensionedValue {
+        value: Value::Float(1.0),
+        unit: Unit {
+            unit: UnitIdentity::Day(86400.0),
+            quantity: Quantity::base(BaseDimension::Time, 1),
+        },
+    };
+    let meters_per_second = DimensionedValue {
+        value: Value::Float(1.0),
+        unit: Unit {
+            unit: UnitIdentity::Base(1.0),
+            quantity: Quantity::from_powers(&[
+                (BaseDimension::Length, 1),
+                (BaseDimension::Time, -1),
+            ]),
+        },
+    };
+
+    let sum = (kilometers_per_day.unwrap() + meters_per_second).unwrap();
+    assert_eq!(sum.value, Value::Float(2.0));
+    assert_eq!(sum.unit.quantity.exponent(BaseDimension::Length), 1);
+    assert_eq!(sum.unit.quantity.exponent(BaseDimension::Time), -1);
+}
+
+#[test]
+fn test_add_mismatched_quantities() {
+    assert!(matches!(
+        Quantity::base(BaseDimension::Length, 1) + Quantity::base(BaseDimension::Length, 2),
+        Err(EvalError::DimensionMismatch {
+            op: BinaryOperation::Add,
+            ..
+        })
+    ));
+
+    // dimensionless values scale, they are not added to dimensioned ones
+    for (lhs, rhs) in [
+        (
+            Quantity::base(BaseDimension::Length, 1),
+            Quantity::dimensionless(),
+        ),
+        (
+            Quantity::dimensionless(),
+            Quantity::base(BaseDimension::Length, 1),
+        ),
+    ] {
+        assert!(matches!(
+            lhs - rhs,
+            Err(EvalError::DimensionMismatch {
+                op: BinaryOperation::Subtract,
+                ..
+            })
+        ));
+    }
+    assert_eq!(
+        Quantity::dimensionless() + Quantity::dimensionless(),
+        Ok(Quantity::dimensionless())
+    );
+}
+
+#[test]
+fn test_vector_broadcasting() {
+    // equal lengths pair up
+    assert_eq!(
+        Value::Vec(vec![1.0, 2.0, 3.0]) + Value::Vec(vec![10.0, 20.0, 30.0]),
+        Ok(Value::Vec(vec![11.0, 22.0, 33.0]))
+    );
+    assert_eq!(
+        Value::Vec(vec![10.0, 20.0]) / Value::Vec(vec![2.0, 4.0]),
+        Ok(Value::Vec(vec![5.0, 5.0]))
+    );
+
+    // scalars and single element vectors broadcast, on either side
+    assert_eq!(
+        Value::Float(2.0) * Value::Vec(vec![1.0, 2.0, 3.0]),
+        Ok(Value::Vec(vec![2.0, 4.0, 6.0]))
+    );
+    assert_eq!(
+        Value::Vec(vec![1.0, 2.0, 3.0]) * Value::Float(2.0),
+        Ok(Value::Vec(vec![2.0, 4.0, 6.0]))
+    );
+    assert_eq!(
+        Value::Vec(vec![10.0]) - Value::Vec(vec![1.0, 2.0]),
+        Ok(Value::Vec(vec![9.0, 8.0]))
+    );
+    assert_eq!(
+        Value::Vec(vec![1.0, 2.0]) - Value::Vec(vec![10.0]),
+        Ok(Value::Vec(vec![-9.0, -8.0]))
+    );
+    assert_eq!(
+        Value::Vec(vec![1.0]) + Value::Vec(vec![2.0]),
+        Ok(Value::Vec(vec![3.0]))
+    );
+
+    // anything else is an error, for every operation, instead of truncating
+    let lhs = Value::Vec(vec![1.0, 2.0, 3.0]);
+    let rhs = Value::Vec(vec![1.0, 2.0]);
+    for result in [
+        lhs.clone() + rhs.clone(),
+        lhs.clone() - rhs.clone(),
+        lhs.clone() * rhs.clone(),
+        lhs.clone() / rhs.clone(),
+    ] {
+        assert!(matches!(
+            result,
+            Err(EvalError::VectorLengthMismatch { lhs: 3, rhs: 2, .. })
+        ));
+    }
+    assert!(matches!(
+        rhs / lhs,
+        Err(EvalError::VectorLengthMismatch { lhs: 2, rhs: 3, .. })
+    ));
+}
+
+#[test]
+fn test_value_errors() {
+    assert!(matches!(
+        Value::Vec(vec![1.0, 2.0, 3.0]) + Value::Vec(vec![1.0, 2.0]),
+        Err(EvalError::VectorLengthMismatch { lhs: 3, rhs: 2, .. })
+    ));
+    assert!(matches!(
+        Value::Float(1.0) / Value::Vec(vec![1.0, 0.0]),
+        Err(EvalError::DivisionByZero { .. })
+    ));
+    assert!(matches!(
+        Value::Float(1.0) / Value::Float(0.0),
+        Err(EvalError::DivisionByZero { .. })
+    ));
+}
+
+#[test]
+fn test_scalar_vector_operand_order() {
+    assert_eq!(
+        Value::Float(10.0) - Value::Vec(vec![1.0, 2.0]),
+        Ok(Value::Vec(vec![9.0, 8.0]))
+    );
+    assert_eq!(
+        Value::Vec(vec![1.0, 2.0]) - Value::Float(10.0),
+        Ok(Value::Vec(vec![-9.0, -8.0]))
+    );
+    assert_eq!(
+        Value::Float(1.0) / Value::Vec(vec![2.0, 4.0]),
+        Ok(Value::Vec(vec![0.5, 0.25]))
+    );
+    assert_eq!(
+        Value::Vec(vec![2.0, 4.0]) / Value::Float(2.0),
+        Ok(Value::Vec(vec![1.0, 2.0]))
+    );
+}
+
+/// xorshift64, so the property tests are reproducible without a dependency
+#[cfg(test)]
+struct TestRng(u64);
+
+#[cfg(test)]
+impl TestRng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> usize {
+        (self.next() % bound) as usize
+    }
+
+    /// never zero, so division is always defined
+    fn float(&mut self) -> f64 {
+        let magnitude = (self.next() % 10000 + 1) as f64 / 100.0;
+        if self.next().is_multiple_of(2) {
+            magnitude
+        } else {
+            -magnitude
+        }
+    }
+
+    /// a scalar, a single element vector or a vector of `length`
+    fn value(&mut self, length: usize) -> Value {
+        match self.below(3) {
+            0 => Value::Float(self.float()),
+            1 => Value::Vec(vec![self.float()]),
+            _ => Value::Vec((0..length).map(|_| self.float()).collect()),
+        }
+    }
+}
+
+/// The element a broadcast value contributes at `index`
+#[cfg(test)]
+fn element(value: &Value, index: usize) -> f64 {
+    match value {
+        Value::Float(scalar) => *scalar,
+        Value::Vec(vector) if vector.len() == 1 => vector[0],
+        Value::Vec(vector) => vector[index],
+    }
+}
+
+#[cfg(test)]
+fn length(value: &Value) -> Option<usize> {
+    match value {
+        Value::Float(_) => None,
+        Value::Vec(vector) => Some(vector.len()),
+    }
+}
+
+#[test]
+fn test_value_arithmetic_matches_scalar_arithmetic() {
+    type ValueOperation = fn(Value, Value) -> Result<Value, EvalError>;
+    type ScalarOperation = fn(f64, f64) -> f64;
+    let operations: [(&str, ValueOperation, ScalarOperation); 4] = [
+        ("+", |lhs, rhs| lhs + rhs, |lhs, rhs| lhs + rhs),
+        ("-", |lhs, rhs| lhs - rhs, |lhs, rhs| lhs - rhs),
+        ("*", |lhs, rhs| lhs * rhs, |lhs, rhs| lhs * rhs),
+        ("/", |lhs, rhs| lhs / rhs, |lhs, rhs| lhs / rhs),
+    ];
+
+    let mut rng = TestRng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..2000 {
+        // mostly compatible lengths, sometimes not
+        let lhs_length = rng.below(5) + 2;
+        let rhs_length = if rng.below(4) == 0 {
+            rng.below(5) + 2
+        } else {
+            lhs_length
+        };
+        let lhs = rng.value(lhs_length);
+        let rhs = rng.value(rhs_length);
+
+        for (symbol, value_operation, scalar_operation) in operations {
+            let result = value_operation(lhs.clone(), rhs.clone());
+            let context = format!("{:?} {} {:?} = {:?}", lhs, symbol, rhs, result);
+
+            match (length(&lhs), length(&rhs)) {
+                (Some(lhs_length), Some(rhs_length))
+                    if lhs_length != rhs_length && lhs_length != 1 && rhs_length != 1 =>
+                {
+                    assert!(
+                        matches!(result, Err(EvalError::VectorLengthMismatch { .. })),
+                        "{}",
+                        context
+                    );
+                }
+                (None, None) => {
+                    let expected = scalar_operation(element(&lhs, 0), element(&rhs, 0));
+                    assert_eq!(result, Ok(Value::Float(expected)), "{}", context);
+                }
+                (lhs_length, rhs_length) => {
+                    let length = lhs_length.max(rhs_length).unwrap();
+                    let expected = (0..length)
+                        .map(|index| scalar_operation(element(&lhs, index), element(&rhs, index)))
+                        .collect();
+                    assert_eq!(result, Ok(Value::Vec(expected)), "{}", context);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_power() {
+    let length = Quantity::base(BaseDimension::Length, 1);
+    let dimensioned = |value, factor, quantity| DimensionedValue {
+        value,
+        unit: Unit {
+            unit: UnitIdentity::Base(factor),
+            quantity,
+        },
+    };
+    let number = |value| dimensioned(Value::Float(value), 1.0, Quantity::dimensionless());
+
+    let cubed = dimensioned(Value::Float(2.0), 1.0, length)
+        .pow(number(3.0))
+        .unwrap();
+    assert_eq!(cubed.value, Value::Float(8.0));
+    assert_eq!(cubed.unit.quantity, length.powi(3).unwrap());
+
+    // converted to base units first, (1[km])^2 is 1000000 m^2
+    let squared = dimensioned(Value::Float(1.0), 1000.0, length)
+        .pow(number(2.0))
+        .unwrap();
+    assert_eq!(squared.value, Value::Float(1e6));
+
+    let root = dimensioned(Value::Vec(vec![4.0, 9.0]), 1.0, length.powi(2).unwrap())
+        .pow(number(0.5))
+        .unwrap();
+    assert_eq!(root.value, Value::Vec(vec![2.0, 3.0]));
+    assert_eq!(root.unit.quantity, length);
+
+    let inverse = dimensioned(Value::Float(4.0), 1.0, length)
+        .pow(number(-1.0))
+        .unwrap();
+    assert_eq!(inverse.value, Value::Float(0.25));
+    assert_eq!(inverse.unit.quantity, length.powi(-1).unwrap());
+
+    assert!(matches!(
+        dimensioned(Value::Float(2.0), 1.0, length).pow(number(0.5)),
+        Err(EvalError::InvalidPower { .. })
+    ));
+    assert!(matches!(
+        dimensioned(Value::Float(2.0), 1.0, length).pow(dimensioned(
+            Value::Vec(vec![1.0, 2.0]),
+            1.0,
+            Quantity::dimensionless()
+        )),
+        Err(EvalError::InvalidPower { .. })
+    ));
+    // an exponent that does not fit in the unit is not saturated
+    assert!(matches!(
+        dimensioned(Value::Float(2.0), 1.0, length).pow(number(3e9)),
+        Err(EvalError::InvalidPower { .. })
+    ));
+    assert!(matches!(
+        number(2.0).pow(dimensioned(Value::Float(2.0), 1.0, length)),
+        Err(EvalError::DimensionedExponent { .. })
+    ));
+
+    // dimensionless values take any powers, element by element
+    let powers = number(2.0)
+        .pow(dimensioned(
+            Value::Vec(vec![0.5, 2.0, 3.0]),
+            1.0,
+            Quantity::dimensionless(),
+        ))
+        .unwrap();
+    assert_eq!(powers.value, Value::Vec(vec![2.0f64.sqrt(), 4.0, 8.0]));
+
+    // zero to a negative power divides by zero, as `/` does
+    assert!(matches!(
+        number(0.0).pow(number(-1.0)),
+        Err(EvalError::DivisionByZero { .. })
+    ));
+    assert!(matches!(
+        Value::Vec(vec![1.0, 0.0]).pow(Value::Vec(vec![2.0, -0.5])),
+        Err(EvalError::DivisionByZero { .. })
+    ));
+    assert_eq!(
+        Value::Vec(vec![0.0, 2.0]).pow(Value::Vec(vec![2.0, -1.0])),
+        Ok(Value::Vec(vec![0.0, 0.5]))
+    );
+    assert!(matches!(
+        Value::Vec(vec![0.0]).pow(Value::Vec(vec![1.0, -1.0])),
+        Err(EvalError::DivisionByZero { .. })
+    ));
+}
+
+#[test]
+fn test_convert_to() {
+    let time = Quantity::base(BaseDimension::Time, 1);
+    let seconds = DimensionedValue {
+        value: Value::Vec(vec![7200.0, 5400.0]),
+        unit: Unit {
+            unit: UnitIdentity::Second(1.0),
+            quantity: time,
+        },
+    };
+    let hours = Unit {
+        unit: UnitIdentity::Hour(3600.0),
+        quantity: time,
+    };
+
+    let converted = seconds.clone().convert_to(hours.clone()).unwrap();
+    assert_eq!(converted.value, Value::Vec(vec![2.0, 1.5]));
+    assert_eq!(converted.unit, hours);
+
+    assert!(matches!(
+        seconds.convert_to(Unit {
+            unit: UnitIdentity::Meter(1.0),
+            quantity: Quantity::base(BaseDimension::Length, 1),
+        }),
+        Err(EvalError::ConversionMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_format_number() {
+    for (value, significant_figures, formatted) in [
+        (1.23, 6, "1.23"),
+        (2.0, 6, "2"),
+        (16.666666666666668, 6, "16.6667"),
+        (16.666666666666668, 2, "17"),
+        (123456789.0, 3, "123000000"),
+        (-0.5, 6, "-0.5"),
+        (0.00015, 6, "0.00015"),
+        (0.0000015, 6, "1.5e-6"),
+        (6.02214076e23, 4, "6.022e23"),
+        (0.0, 6, "0"),
+        (-0.0, 6, "0"),
+        (9.9999999, 3, "10"),
+    ] {
+        assert_eq!(
+            format_number(value, significant_figures),
+            formatted,
+            "{} to {}",
+            value,
+            significant_figures
+        );
+    }
+}
+
+#[test]
+fn test_display() {
+    let kilometers_per_hour = units::resolve("km", 1)
+        .unwrap()
+        .product(units::resolve("h", -1).unwrap())
+        .unwrap();
+    let speed = DimensionedValue {
+        value: Value::Float(1.23),
+        unit: kilometers_per_hour.clone(),
+    };
+    assert_eq!(speed.to_string(), "1.23 km/h");
+    assert_eq!(format!("{:.2}", speed), "1.2 km/h");
+
+    let distances = DimensionedValue {
+        value: Value::Vec(vec![1.0, 2.0, 3.0]),
+        unit: units::resolve("m", 1).unwrap(),
+    };
+    assert_eq!(distances.to_string(), "[1, 2, 3] m");
+
+    let acceleration = kilometers_per_hour
+        .product(units::resolve("s", -1).unwrap())
+        .unwrap();
+    assert_eq!(acceleration.to_string(), "km/h·s");
+    assert_eq!(format!("{:#}", acceleration), "km/h/s");
+
+    // results of arithmetic are in base units, which simplify
+    let area = (distances.clone() * distances).unwrap();
+    assert_eq!(area.to_string(), "[1, 4, 9] m²");
+    assert_eq!(format!("{:#}", area), "[1, 4, 9] m^2");
+
+    // derived units are only used when asked for, for computed values and
+    // values written in base units alike
+    let energy = DimensionedValue {
+        value: Value::Float(2.0),
+        unit: Unit {
+            unit: UnitIdentity::Base(1.0),
+            quantity: units::lookup("J").unwrap().quantity,
+        },
+    };
+    assert_eq!(energy.to_string(), "2 kg·m²/s²");
+    assert_eq!(energy.in_derived_units().to_string(), "2 J");
+
+    let kilojoules = ["kg", "km", "m"]
+        .into_iter()
+        .map(|symbol| units::resolve(symbol, 1).unwrap())
+        .try_fold(units::resolve("s", -2).unwrap(), Unit::product)
+        .unwrap();
+    let written = DimensionedValue {
+        value: Value::Float(2.0),
+        unit: kilojoules,
+    };
+    assert_eq!(written.to_string(), "2 kg·km·m/s²");
+    assert_eq!(written.in_derived_units().to_string(), "2000 J");
+
+    let per_second = DimensionedValue {
+        value: Value::Float(50.0),
+        unit: units::resolve("s", -1).unwrap(),
+    };
+    assert_eq!(per_second.in_derived_units().to_string(), "50 Hz");
+
+    // a named unit keeps its name
+    let litres = DimensionedValue {
+        value: Value::Float(3.0),
+        unit: units::resolve("L", 1).unwrap(),
+    };
+    assert_eq!(litres.in_derived_units().to_string(), "3 L");
+    assert_eq!(Value::Float(0.5).to_string(), "0.5");
+    assert_eq!(
+        DimensionedValue {
+            value: Value::Float(0.15),
+            unit: Unit::dimensionless(),
+        }
+        .to_string(),
+        "0.15"
+    );
+
+    // km/m cancels to a plain number, but keeps its factor
+    let ratio = units::resolve("km", 1)
+        .unwrap()
+        .product(units::resolve("m", -1).unwrap())
+        .unwrap();
+    assert!(ratio.quantity.is_dimensionless());
+    assert_eq!(ratio.get_conversion_factor(), 1000.0);
+}
@@ -0,0 +1,663 @@
+use std::sync::OnceLock;
+
+use super::types::*;
+
+/// A named unit: how to convert it to the coherent SI unit of its quantity,
+/// and which quantity that is
+#[derive(Debug, Clone)]
+pub struct UnitDefinition {
+    pub symbol: &'static str,
+    pub aliases: &'static [&'static str],
+    pub identity: fn(f64) -> UnitIdentity,
+    pub conversion_factor: f64,
+    pub quantity: Quantity,
+    /// derived units (J, W, ..) can stand in for their base unit expansion when printing
+    pub derived: bool,
+    /// whether SI prefixes may be attached, km and mWh but not kmin or kUSD
+    pub prefixable: bool,
+}
+
+impl UnitDefinition {
+    /// this unit raised to `power`: m^2 converts to m^2 with 1.
+    /// None when the exponents of its quantity overflow, L^1000000000
+    pub fn to_unit(&self, power: i32) -> Option<Unit> {
+        self.to_prefixed_unit(1.0, power)
+    }
+
+    /// the prefix scales the unit before the power applies: km^2 converts to m^2 with 1000^2
+    pub fn to_prefixed_unit(&self, prefix_factor: f64, power: i32) -> Option<Unit> {
+        Some(Unit {
+            unit: (self.identity)((prefix_factor * self.conversion_factor).powi(power)),
+            quantity: self.quantity.powi(power).ok()?,
+        })
+    }
+
+    /// The aliases prefix symbols attach to: the symbol and its spellings in
+    /// another case, L and l, so both mL and ml are millilitres
+    fn symbols(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.aliases
+            .iter()
+            .copied()
+            .filter(|alias| alias.eq_ignore_ascii_case(self.symbol))
+    }
+
+    fn prefixable(self) -> UnitDefinition {
+        UnitDefinition {
+            prefixable: true,
+            ..self
+        }
+    }
+}
+
+/// An SI prefix, `symbol` attaches to unit symbols (km), `name` to unit names (kilometers)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prefix {
+    pub symbol: &'static str,
+    pub name: &'static str,
+    pub factor: f64,
+}
+
+const fn prefix(symbol: &'static str, name: &'static str, factor: f64) -> Prefix {
+    Prefix {
+        symbol,
+        name,
+        factor,
+    }
+}
+
+/// da comes before d, so that dam is a decameter
+pub const PREFIXES: [Prefix; 21] = [
+    prefix("Y", "yotta", 1e24),
+    prefix("Z", "zetta", 1e21),
+    prefix("E", "exa", 1e18),
+    prefix("P", "peta", 1e15),
+    prefix("T", "tera", 1e12),
+    prefix("G", "giga", 1e9),
+    prefix("M", "mega", 1e6),
+    prefix("k", "kilo", 1e3),
+    prefix("h", "hecto", 1e2),
+    prefix("da", "deca", 1e1),
+    prefix("d", "deci", 1e-1),
+    prefix("c", "centi", 1e-2),
+    prefix("m", "milli", 1e-3),
+    prefix("µ", "micro", 1e-6),
+    prefix("u", "micro", 1e-6), // ascii spelling of µ
+    prefix("n", "nano", 1e-9),
+    prefix("p", "pico", 1e-12),
+    prefix("f", "femto", 1e-15),
+    prefix("a", "atto", 1e-18),
+    prefix("z", "zepto", 1e-21),
+    prefix("y", "yocto", 1e-24),
+];
+
+fn base(dimension: BaseDimension) -> Quantity {
+    Quantity::base(dimension, 1)
+}
+
+fn unit(
+    symbol: &'static str,
+    aliases: &'static [&'static str],
+    identity: fn(f64) -> UnitIdentity,
+    conversion_factor: f64,
+    quantity: Quantity,
+) -> UnitDefinition {
+    UnitDefinition {
+        symbol,
+        aliases,
+        identity,
+        conversion_factor,
+        quantity,
+        derived: false,
+        prefixable: false,
+    }
+}
+
+fn derived_unit(
+    symbol: &'static str,
+    aliases: &'static [&'static str],
+    identity: fn(f64) -> UnitIdentity,
+    conversion_factor: f64,
+    quantity: Quantity,
+) -> UnitDefinition {
+    UnitDefinition {
+        derived: true,
+        ..unit(symbol, aliases, identity, conversion_factor, quantity)
+    }
+}
+
+/// Every unit the parser knows by name
+pub fn registry() -> &'static [UnitDefinition] {
+    static REGISTRY: OnceLock<Vec<UnitDefinition>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        use BaseDimension::*;
+
+        let length = base(Length);
+        let mass = base(Mass);
+        let time = base(Time);
+        let energy = Quantity::from_powers(&[(Mass, 1), (Length, 2), (Time, -2)]);
+
+        vec![
+            unit(
+                "none",
+                &["none"],
+                UnitIdentity::None,
+                1.0,
+                Quantity::dimensionless(),
+            ),
+            // time
+            unit(
+                "s",
+                &["seconds", "second", "s"],
+                UnitIdentity::Second,
+                1.0,
+                time,
+            )
+            .prefixable(),
+            unit(
+                "min",
+                &["minutes", "minute", "min"],
+                UnitIdentity::Minute,
+                60.0,
+                time,
+            ),
+            unit(
+                "h",
+                &["hours", "hour", "h"],
+                UnitIdentity::Hour,
+                3600.0,
+                time,
+            ),
+            unit(
+                "day",
+                &["days", "day", "d"],
+                UnitIdentity::Day,
+                86400.0,
+                time,
+            ),
+            unit(
+                "week",
+                &["weeks", "week", "wk"],
+                UnitIdentity::Week,
+                604800.0,
+                time,
+            ),
+            // average gregorian month and year
+            unit(
+                "month",
+                &["months", "month"],
+                UnitIdentity::Month,
+                2629746.0,
+                time,
+            ),
+            unit(
+                "year",
+                &["years", "year", "yr"],
+                UnitIdentity::Year,
+                31556952.0,
+                time,
+            ),
+            // length
+            unit(
+                "m",
+                &["meters", "meter", "m"],
+                UnitIdentity::Meter,
+                1.0,
+                length,
+            )
+            .prefixable(),
+            // mass, the kilogram, not the gram, is the SI base unit, so it is listed
+            // on its own rather than found as a prefixed gram
+            unit(
+                "g",
+                &["grams", "gram", "g"],
+                UnitIdentity::Gram,
+                0.001,
+                mass,
+            )
+            .prefixable(),
+            unit(
+                "kg",
+                &["kilograms", "kilogram", "kg"],
+                UnitIdentity::Kilogram,
+                1.0,
+                mass,
+            ),
+            // absolute temperatures only, there are no offset scales like celsius
+            unit(
+                "K",
+                &["kelvin", "K"],
+                UnitIdentity::Kelvin,
+                1.0,
+                base(Temperature),
+            )
+            .prefixable(),
+            unit(
+                "mol",
+                &["moles", "mole", "mol"],
+                UnitIdentity::Mole,
+                1.0,
+                base(AmountOfSubstance),
+            )
+            .prefixable(),
+            unit(
+                "A",
+                &["amperes", "ampere", "amp", "A"],
+                UnitIdentity::Ampere,
+                1.0,
+                base(ElectricCurrent),
+            )
+            .prefixable(),
+            unit(
+                "cd",
+                &["candelas", "candela", "cd"],
+                UnitIdentity::Candela,
+                1.0,
+                base(LuminousIntensity),
+            )
+            .prefixable(),
+            // currency, USD is the base unit
+            unit("USD", &["USD", "$"], UnitIdentity::USD, 1.0, base(Currency)),
+            unit("GBP", &["GBP", "£"], UnitIdentity::GBP, 1.2, base(Currency)),
+            // derived
+            derived_unit(
+                "N",
+                &["newtons", "newton", "N"],
+                UnitIdentity::Newton,
+                1.0,
+                Quantity::from_powers(&[(Mass, 1), (Length, 1), (Time, -2)]),
+            )
+            .prefixable(),
+            derived_unit(
+                "J",
+                &["joules", "joule", "J"],
+                UnitIdentity::Joule,
+                1.0,
+                energy,
+            )
+            .prefixable(),
+            derived_unit(
+                "W",
+                &["watts", "watt", "W"],
+                UnitIdentity::Watt,
+                1.0,
+                Quantity::from_powers(&[(Mass, 1), (Length, 2), (Time, -3)]),
+            )
+            .prefixable(),
+            derived_unit(
+                "Pa",
+                &["pascals", "pascal", "Pa"],
+                UnitIdentity::Pascal,
+                1.0,
+                Quantity::from_powers(&[(Mass, 1), (Length, -1), (Time, -2)]),
+            )
+            .prefixable(),
+            derived_unit(
+                "Hz",
+                &["hertz", "Hz"],
+                UnitIdentity::Hertz,
+                1.0,
+                Quantity::base(Time, -1),
+            )
+            .prefixable(),
+            derived_unit("Wh", &["Wh"], UnitIdentity::WattHour, 3600.0, energy).prefixable(),
+            derived_unit(
+                "L",
+                &["litres", "litre", "liters", "liter", "L", "l"],
+                UnitIdentity::Litre,
+                0.001,
+                Quantity::base(Length, 3),
+            )
+            .prefixable(),
+            derived_unit(
+                "ha",
+                &["hectares", "hectare", "ha"],
+                UnitIdentity::Hectare,
+                10000.0,
+                Quantity::base(Length, 2),
+            ),
+        ]
+    })
+}
+
+/// Find the unit named `alias`, aliases are matched exactly
+pub fn lookup(alias: &str) -> Option<&'static UnitDefinition> {
+    registry()
+        .iter()
+        .find(|definition| definition.aliases.contains(&alias))
+}
+
+/// Find the unit named `alias` raised to `power`, which may carry an SI prefix:
+/// km, kilometers, MWh, µs. Exact names win, so min is a minute and not a milli-inch.
+/// None as well when the power overflows the exponents of the unit
+pub fn resolve(alias: &str, power: i32) -> Option<Unit> {
+    if let Some(definition) = lookup(alias) {
+        return definition.to_unit(power);
+    }
+
+    PREFIXES.iter().find_map(|prefix| {
+        let by_symbol = alias.strip_prefix(prefix.symbol).and_then(|symbol| {
+            registry().iter().find(|definition| {
+                definition.prefixable && definition.symbols().any(|alias| alias == symbol)
+            })
+        });
+        let by_name = alias.strip_prefix(prefix.name).and_then(|name| {
+            registry().iter().find(|definition| {
+                definition.prefixable
+                    && !definition.symbols().any(|alias| alias == name)
+                    && definition.aliases.contains(&name)
+            })
+        });
+        by_symbol
+            .or(by_name)
+            .and_then(|definition| definition.to_prefixed_unit(prefix.factor, power))
+    })
+}
+
+/// A dimension named in an annotation raised to `power`: length, currency,
+/// or dimensionless for a plain number
+pub fn resolve_dimension(name: &str, power: i32) -> Option<Quantity> {
+    if name == "dimensionless" {
+        return Some(Quantity::dimensionless());
+    }
+    BaseDimension::ALL
+        .into_iter()
+        .find(|dimension| dimension.name() == name)
+        .map(|dimension| Quantity::base(dimension, power))
+}
+
+/// The coherent derived unit for a quantity, kg·m²/s² is J
+pub fn derived_unit_for(quantity: &Quantity) -> Option<Unit> {
+    registry()
+        .iter()
+        .find(|definition| {
+            definition.derived
+                && definition.conversion_factor == 1.0
+                && definition.quantity == *quantity
+        })
+        .map(|definition| Unit {
+            unit: (definition.identity)(1.0),
+            quantity: definition.quantity,
+        })
+}
+
+fn superscript(exponent: i32) -> String {
+    exponent
+        .to_string()
+        .chars()
+        .map(|digit| match digit {
+            '-' => '⁻',
+            '0' => '⁰',
+            '1' => '¹',
+            '2' => '²',
+            '3' => '³',
+            '4' => '⁴',
+            '5' => '⁵',
+            '6' => '⁶',
+            '7' => '⁷',
+            '8' => '⁸',
+            _ => '⁹',
+        })
+        .collect()
+}
+
+fn format_term(symbol: &str, power: i32, ascii: bool) -> String {
+    match (power, ascii) {
+        (1, false) => symbol.to_string(),
+        (1, true) => symbol.replace('µ', "u"),
+        (_, false) => format!("{}{}", symbol, superscript(power)),
+        (_, true) => format!("{}^{}", symbol.replace('µ', "u"), power),
+    }
+}
+
+/// Unit symbols and their powers written as one unit, [("kg", 1), ("m", 2),
+/// ("s", -2)] is kg·m²/s². In `ascii` it is written as source that parses
+/// back to the same unit: every term of the denominator has its own `/`,
+/// km/h/s rather than km/h*s, a denominator alone is a negative power, s^-1,
+/// and symbols that cancel stay, m/m. Without `ascii` they are left out
+pub fn format_terms(terms: &[(String, i32)], ascii: bool) -> String {
+    let separator = if ascii { "*" } else { "·" };
+    let numerator: Vec<String> = terms
+        .iter()
+        .filter(|(_, power)| *power > 0 || (ascii && *power == 0))
+        .map(|(symbol, power)| format_term(symbol, (*power).max(1), ascii))
+        .collect();
+    let denominator: Vec<String> = terms
+        .iter()
+        .filter(|(_, power)| *power < 0 || (ascii && *power == 0))
+        .map(|(symbol, power)| format_term(symbol, (-power).max(1), ascii))
+        .collect();
+
+    match (numerator.is_empty(), denominator.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => numerator.join(separator),
+        (true, false) if ascii => terms
+            .iter()
+            .map(|(symbol, power)| format_term(symbol, *power, ascii))
+            .collect::<Vec<String>>()
+            .join(separator),
+        (true, false) => format!("1/{}", denominator.join(separator)),
+        (false, false) if ascii => {
+            format!("{}/{}", numerator.join(separator), denominator.join("/"))
+        }
+        (false, false) => format!(
+            "{}/{}",
+            numerator.join(separator),
+            denominator.join(separator)
+        ),
+    }
+}
+
+/// Mass first, so energy reads kg·m²/s² as it is usually written
+const DISPLAY_ORDER: [BaseDimension; 8] = [
+    BaseDimension::Mass,
+    BaseDimension::Length,
+    BaseDimension::Time,
+    BaseDimension::Temperature,
+    BaseDimension::AmountOfSubstance,
+    BaseDimension::ElectricCurrent,
+    BaseDimension::LuminousIntensity,
+    BaseDimension::Currency,
+];
+
+/// The base unit symbols a quantity is stored in, with their powers
+pub fn base_terms(quantity: &Quantity) -> Vec<(String, i32)> {
+    DISPLAY_ORDER
+        .iter()
+        .filter(|dimension| quantity.exponent(**dimension) != 0)
+        .map(|dimension| {
+            (
+                dimension.base_symbol().to_string(),
+                quantity.exponent(*dimension),
+            )
+        })
+        .collect()
+}
+
+/// The symbol of the base unit a quantity is stored in, e.g. kg·m²/s²
+pub fn symbol_for(quantity: &Quantity) -> String {
+    format_terms(&base_terms(quantity), false)
+}
+
+/// The symbol and power a named unit is written with, Meter(1000000.0) with
+/// a Length^2 quantity is ("km", 2). None for units without a name
+pub fn named_term(unit: &Unit) -> Option<(String, i32)> {
+    let definition = registry().iter().find(|definition| {
+        !definition.quantity.is_dimensionless()
+            && std::mem::discriminant(&(definition.identity)(1.0))
+                == std::mem::discriminant(&unit.unit)
+    })?;
+    let power = BaseDimension::ALL.into_iter().find_map(|dimension| {
+        match definition.quantity.exponent(dimension) {
+            0 => None,
+            exponent => Some(unit.quantity.exponent(dimension) / exponent),
+        }
+    })?;
+    if power == 0 || definition.quantity.powi(power) != Ok(unit.quantity) {
+        return None;
+    }
+
+    // whatever the power does not explain is the prefix
+    let prefix_factor =
+        unit.get_conversion_factor().powf(1.0 / power as f64) / definition.conversion_factor;
+    let close = |factor: f64| (prefix_factor / factor - 1.0).abs() < 1e-9;
+    let prefix = if close(1.0) {
+        ""
+    } else {
+        PREFIXES
+            .iter()
+            .find(|prefix| definition.prefixable && close(prefix.factor))?
+            .symbol
+    };
+
+    Some((format!("{}{}", prefix, definition.symbol), power))
+}
+
+#[test]
+fn test_lookup() {
+    let joule = lookup("joules").unwrap();
+    assert_eq!(joule.symbol, "J");
+    assert_eq!(lookup("Wh").unwrap().quantity, joule.quantity);
+    assert!(lookup("furlongs").is_none());
+}
+
+#[test]
+fn test_resolve_dimension() {
+    assert_eq!(
+        resolve_dimension("time", -1),
+        Some(Quantity::base(BaseDimension::Time, -1))
+    );
+    assert_eq!(
+        resolve_dimension("dimensionless", 1),
+        Some(Quantity::dimensionless())
+    );
+    // units are not dimensions
+    assert_eq!(resolve_dimension("m", 1), None);
+}
+
+#[test]
+fn test_resolve_prefixes() {
+    let length = Quantity::base(BaseDimension::Length, 1);
+    let time = Quantity::base(BaseDimension::Time, 1);
+
+    for (alias, identity, quantity) in [
+        ("km", UnitIdentity::Meter(1e3), length),
+        ("kilometers", UnitIdentity::Meter(1e3), length),
+        ("mm", UnitIdentity::Meter(1e-3), length),
+        ("Mm", UnitIdentity::Meter(1e6), length),
+        ("dam", UnitIdentity::Meter(1e1), length),
+        ("ms", UnitIdentity::Second(1e-3), time),
+        ("µs", UnitIdentity::Second(1e-6), time),
+        ("min", UnitIdentity::Minute(60.0), time),
+        (
+            "kg",
+            UnitIdentity::Kilogram(1.0),
+            Quantity::base(BaseDimension::Mass, 1),
+        ),
+        (
+            "mg",
+            UnitIdentity::Gram(1e-6),
+            Quantity::base(BaseDimension::Mass, 1),
+        ),
+        // both spellings of the litre take prefixes
+        (
+            "mL",
+            UnitIdentity::Litre(1e-6),
+            Quantity::base(BaseDimension::Length, 3),
+        ),
+        (
+            "ml",
+            UnitIdentity::Litre(1e-6),
+            Quantity::base(BaseDimension::Length, 3),
+        ),
+        (
+            "millilitres",
+            UnitIdentity::Litre(1e-6),
+            Quantity::base(BaseDimension::Length, 3),
+        ),
+    ] {
+        let unit = resolve(alias, 1).unwrap();
+        assert_eq!(unit.quantity, quantity, "{}", alias);
+        assert_eq!(unit.unit, identity, "{}", alias);
+    }
+
+    let megawatt_hours = resolve("MWh", 1).unwrap();
+    assert_eq!(megawatt_hours.unit, UnitIdentity::WattHour(3.6e9));
+
+    let square_kilometers = resolve("km", 2).unwrap();
+    assert_eq!(square_kilometers.unit, UnitIdentity::Meter(1e6));
+    assert_eq!(square_kilometers.quantity, length.powi(2).unwrap());
+
+    // not prefixable
+    assert!(resolve("kmin", 1).is_none());
+    assert!(resolve("kUSD", 1).is_none());
+    assert!(resolve("kkg", 1).is_none());
+}
+
+#[test]
+fn test_named_term() {
+    for (alias, power, symbol) in [
+        ("km", 2, "km"),
+        ("m", 1, "m"),
+        ("h", -1, "h"),
+        ("kg", 1, "kg"),
+        ("mg", 1, "mg"),
+        ("MWh", 1, "MWh"),
+        ("µs", 3, "µs"),
+        ("us", 1, "µs"),
+    ] {
+        let unit = resolve(alias, power).unwrap();
+        assert_eq!(
+            named_term(&unit),
+            Some((symbol.to_string(), power)),
+            "{}",
+            alias
+        );
+    }
+    assert_eq!(named_term(&Unit::dimensionless()), None);
+}
+
+#[test]
+fn test_format_terms() {
+    let terms = vec![
+        ("km".to_string(), 1),
+        ("µs".to_string(), -2),
+        ("A".to_string(), 1),
+    ];
+    assert_eq!(format_terms(&terms, false), "km·A/µs²");
+    assert_eq!(format_terms(&terms, true), "km*A/us^2");
+
+    let terms = |terms: &[(&str, i32)]| -> Vec<(String, i32)> {
+        terms
+            .iter()
+            .map(|(symbol, power)| (symbol.to_string(), *power))
+            .collect()
+    };
+    assert_eq!(format_terms(&terms(&[("s", -1)]), false), "1/s");
+    assert_eq!(format_terms(&terms(&[("s", -1)]), true), "s^-1");
+    assert_eq!(
+        format_terms(&terms(&[("m", -2), ("s", -1)]), true),
+        "m^-2*s^-1"
+    );
+    let acceleration = terms(&[("km", 1), ("h", -1), ("s", -1)]);
+    assert_eq!(format_terms(&acceleration, false), "km/h·s");
+    assert_eq!(format_terms(&acceleration, true), "km/h/s");
+    // symbols that cancel are kept in source, but not when displayed
+    assert_eq!(format_terms(&terms(&[("m", 0)]), true), "m/m");
+    assert_eq!(format_terms(&terms(&[("m", 0)]), false), "");
+    assert_eq!(format_terms(&terms(&[("m", 0), ("s", -1)]), true), "m/m/s");
+}
+
+#[test]
+fn test_symbol_for() {
+    let energy = lookup("J").unwrap().quantity;
+    assert_eq!(symbol_for(&energy), "kg·m²/s²");
+    assert_eq!(
+        derived_unit_for(&energy).unwrap().unit,
+        UnitIdentity::Joule(1.0)
+    );
+
+    let velocity = (lookup("m").unwrap().quantity / lookup("s").unwrap().quantity).unwrap();
+    assert_eq!(symbol_for(&velocity), "m/s");
+    assert!(derived_unit_for(&velocity).is_none());
+    assert_eq!(symbol_for(&Quantity::dimensionless()), "");
+}